@@ -1,6 +1,12 @@
-use crate::{protocol::HASH_LEN, utils::ConnectionMode};
-use sha2::{Digest, Sha224};
-use std::fmt::Write;
+use crate::{
+    user_db::{self, UserDb},
+    utils::{ConnectionMode, ListenEndpoint, ProxyProtocolMode},
+};
+#[cfg(feature = "quic")]
+use crate::tunnel::quic::CongestionControl;
+#[cfg(feature = "quic")]
+use crate::tunnel::remote_forward::{parse_remote_forward, RemoteForwardSpec};
+use anyhow::{Context, Result};
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
@@ -42,10 +48,34 @@ fn parse_connection_mode(l: &str) -> ConnectionMode {
     }
 }
 
+fn parse_proxy_protocol_mode(l: &str) -> ProxyProtocolMode {
+    ProxyProtocolMode::parse(l)
+}
+
+#[cfg(feature = "quic")]
+fn parse_congestion_control(l: &str) -> CongestionControl {
+    match &l.to_lowercase()[..] {
+        "newreno" => CongestionControl::NewReno,
+        "bbr" => CongestionControl::Bbr,
+        _ => CongestionControl::Cubic,
+    }
+}
+
 fn parse_addr(l: &str) -> String {
     "127.0.0.1:".to_owned() + l
 }
 
+/// Accepts either a bare port (assumed to be `127.0.0.1:<port>`), a full
+/// `host:port`, or (behind the `unix_socket` feature) a `unix:/path/to.sock`
+/// endpoint.
+fn parse_listen_endpoint(l: &str) -> ListenEndpoint {
+    if l.contains(':') {
+        ListenEndpoint::parse(l)
+    } else {
+        ListenEndpoint::parse(&parse_addr(l))
+    }
+}
+
 // fn parse_port(l: &str) -> u16 {
 //     let mut res = 0;
 //     for i in l.bytes() {
@@ -59,14 +89,7 @@ fn parse_addr(l: &str) -> String {
 // }
 
 fn password_to_hash(s: &str) -> Arc<String> {
-    let mut hasher = Sha224::new();
-    hasher.update(s);
-    let h = hasher.finalize();
-    let mut s = String::with_capacity(HASH_LEN);
-    for i in h {
-        write!(&mut s, "{:02x}", i).unwrap();
-    }
-    Arc::new(s)
+    Arc::new(user_db::hash_password(s))
 }
 
 fn arc_string(s: &str) -> Arc<String> {
@@ -76,13 +99,17 @@ fn arc_string(s: &str) -> Arc<String> {
 #[derive(StructOpt, Debug, Clone)]
 #[structopt(name = "basic")]
 pub struct Opt {
+    /// Listen address for the HTTP inbound: a port (bound on 127.0.0.1), a
+    /// `host:port`, or a `unix:/path/to.sock` endpoint.
     #[cfg(feature = "client")]
-    #[structopt(short = "h", long = "http_port", default_value = "8888", parse(from_str = parse_addr))]
-    pub local_http_addr: String,
+    #[structopt(short = "h", long = "http_port", default_value = "8888", parse(from_str = parse_listen_endpoint))]
+    pub local_http_addr: ListenEndpoint,
 
+    /// Listen address for the SOCKS5 inbound: a port (bound on 127.0.0.1), a
+    /// `host:port`, or a `unix:/path/to.sock` endpoint.
     #[cfg(feature = "client")]
-    #[structopt(short = "5", long = "socks5_port", default_value = "8889", parse(from_str = parse_addr))]
-    pub local_socks5_addr: String,
+    #[structopt(short = "5", long = "socks5_port", default_value = "8889", parse(from_str = parse_listen_endpoint))]
+    pub local_socks5_addr: ListenEndpoint,
 
     #[structopt(short = "l", long, default_value = "info", parse(from_str = parse_log_level))]
     pub log_level: tracing::Level,
@@ -102,6 +129,13 @@ pub struct Opt {
     #[structopt(short, long)]
     pub server: bool,
 
+    /// TOML (or JSON, by extension) config file providing defaults for any
+    /// flag not explicitly passed on the command line, so a deployment
+    /// with many users, certificate paths and transport knobs doesn't have
+    /// to be a long flag list. CLI flags always override the file.
+    #[structopt(parse(from_os_str), long = "config")]
+    pub config: Option<PathBuf>,
+
     /// TLS private key in PEM format
     #[cfg(feature = "server")]
     #[structopt(parse(from_os_str), short = "k", long = "key", requires = "cert")]
@@ -115,6 +149,19 @@ pub struct Opt {
     #[structopt(short = "w", long, parse(from_str = password_to_hash))]
     pub password_hash: Arc<String>,
 
+    /// Additional accounts as `name:password`; repeat for more than one.
+    /// Combined with `--users` and the legacy `-w`/`--password-hash` flag into a
+    /// single user registry keyed by password hash.
+    #[cfg(feature = "server")]
+    #[structopt(long = "user")]
+    pub users_inline: Vec<String>,
+
+    /// File of `name:password` lines (one account per line, `#`-prefixed
+    /// lines ignored), loaded into the same user registry as `--user`.
+    #[cfg(feature = "server")]
+    #[structopt(parse(from_os_str), long = "users")]
+    pub users_file: Option<PathBuf>,
+
     #[cfg(feature = "server")]
     #[structopt(short = "f", long, parse(from_str = arc_string))]
     pub fallback_port: Arc<String>,
@@ -122,6 +169,184 @@ pub struct Opt {
     #[cfg(feature = "client")]
     #[structopt(short = "m", long, default_value = "quic", parse(from_str = parse_connection_mode))]
     pub connection_mode: ConnectionMode,
+
+    /// PROXY protocol header written on the outbound trojan stream right
+    /// after the trojan request header, ahead of the relayed payload, so a
+    /// downstream service can recover the real client address: `disabled`,
+    /// `v1` (text) or `v2` (binary).
+    #[cfg(feature = "client")]
+    #[structopt(long = "proxy-protocol", default_value = "disabled", parse(from_str = parse_proxy_protocol_mode))]
+    pub proxy_protocol_mode: ProxyProtocolMode,
+
+    /// Max idle QUIC connections to the proxy kept alive in the connection
+    /// pool, so new tunnel streams can reuse an existing handshake instead
+    /// of paying for a fresh one.
+    #[cfg(feature = "quic")]
+    #[structopt(long = "pool-size", default_value = "4")]
+    pub pool_size: usize,
+
+    /// QUIC transport idle timeout, in seconds, before an unresponsive
+    /// connection is dropped.
+    #[cfg(feature = "quic")]
+    #[structopt(long = "quic-idle-timeout", default_value = "30")]
+    pub quic_idle_timeout: u64,
+
+    /// QUIC keep-alive interval, in seconds; `0` disables keep-alives.
+    #[cfg(feature = "quic")]
+    #[structopt(long = "quic-keepalive", default_value = "10")]
+    pub quic_keepalive: u64,
+
+    /// Max concurrent bidirectional streams a QUIC connection will accept.
+    #[cfg(feature = "quic")]
+    #[structopt(long = "quic-max-bidi-streams", default_value = "128")]
+    pub quic_max_bidi_streams: u64,
+
+    /// Initial congestion window, in bytes, for new QUIC connections.
+    #[cfg(feature = "quic")]
+    #[structopt(long = "quic-initial-window", default_value = "14720")]
+    pub quic_initial_window: u64,
+
+    /// Congestion controller used for QUIC connections: `cubic`, `newreno`
+    /// or `bbr`. `bbr` is accepted but runs cubic (with a warning), since
+    /// this build has no BBR controller.
+    #[cfg(feature = "quic")]
+    #[structopt(long = "congestion", default_value = "cubic", parse(from_str = parse_congestion_control))]
+    pub congestion_control: CongestionControl,
+
+    /// ALPN protocol identifier negotiated on the QUIC handshake, so the
+    /// tunnel can blend into other QUIC deployments instead of always
+    /// announcing itself as `hq-29`.
+    #[cfg(feature = "quic")]
+    #[structopt(long = "quic-alpn", default_value = "hq-29")]
+    pub quic_alpn: String,
+
+    /// Reverse port forward: `<bind_host>:<bind_port>:<local_host>:<local_port>[/tcp|udp]`.
+    /// The server binds `bind_addr` and tunnels every inbound connection
+    /// back through this connection to `local_addr` on the client. Repeat
+    /// for more than one forward.
+    #[cfg(feature = "quic")]
+    #[structopt(long = "remote-forward", parse(from_str = parse_remote_forward))]
+    pub remote_forward: Vec<RemoteForwardSpec>,
+}
+
+impl Opt {
+    /// Parses CLI arguments, then — if `--config` names a file — overlays
+    /// its values onto every field the user didn't pass explicitly on the
+    /// command line. Precedence is CLI flag > config file > structopt's
+    /// built-in default. `-w`/`--password-hash` and (with the `server`
+    /// feature) `-f`/`--fallback-port` have no built-in default, so clap
+    /// requires them on the command line and the file's `password` and
+    /// `fallback_port` values never take effect.
+    pub fn load_with_config() -> Result<Self> {
+        let matches = Self::clap().get_matches();
+        let mut opt = Self::from_clap(&matches);
+
+        let config_path = match opt.config.clone() {
+            Some(path) => path,
+            None => return Ok(opt),
+        };
+
+        let config = crate::config_file::load(&config_path)
+            .with_context(|| format!("failed to load --config file {:?}", config_path))?;
+
+        macro_rules! overlay {
+            ($field:ident, $value:expr) => {
+                if matches.occurrences_of(stringify!($field)) == 0 {
+                    if let Some(v) = $value {
+                        opt.$field = v;
+                    }
+                }
+            };
+        }
+
+        #[cfg(feature = "client")]
+        overlay!(
+            local_http_addr,
+            config.local_http_addr.as_deref().map(parse_listen_endpoint)
+        );
+        #[cfg(feature = "client")]
+        overlay!(
+            local_socks5_addr,
+            config.local_socks5_addr.as_deref().map(parse_listen_endpoint)
+        );
+        overlay!(log_level, config.log_level.as_deref().map(parse_log_level));
+        overlay!(ca, config.ca.clone());
+        overlay!(proxy_url, config.proxy_url.clone());
+        overlay!(proxy_port, config.proxy_port.clone());
+        overlay!(proxy_ip, config.proxy_ip.clone());
+        overlay!(server, config.server);
+        #[cfg(feature = "server")]
+        overlay!(key, config.key.clone());
+        #[cfg(feature = "server")]
+        overlay!(cert, config.cert.clone());
+        overlay!(
+            password_hash,
+            config.password.as_deref().map(password_to_hash)
+        );
+        #[cfg(feature = "server")]
+        overlay!(users_inline, config.users.clone());
+        #[cfg(feature = "server")]
+        overlay!(users_file, config.users_file.clone());
+        #[cfg(feature = "server")]
+        overlay!(fallback_port, config.fallback_port.as_deref().map(arc_string));
+        #[cfg(feature = "client")]
+        overlay!(
+            connection_mode,
+            config.connection_mode.as_deref().map(parse_connection_mode)
+        );
+        #[cfg(feature = "client")]
+        overlay!(
+            proxy_protocol_mode,
+            config
+                .proxy_protocol
+                .as_deref()
+                .map(parse_proxy_protocol_mode)
+        );
+        #[cfg(feature = "quic")]
+        overlay!(pool_size, config.pool_size);
+        #[cfg(feature = "quic")]
+        overlay!(quic_idle_timeout, config.quic_idle_timeout);
+        #[cfg(feature = "quic")]
+        overlay!(quic_keepalive, config.quic_keepalive);
+        #[cfg(feature = "quic")]
+        overlay!(quic_max_bidi_streams, config.quic_max_bidi_streams);
+        #[cfg(feature = "quic")]
+        overlay!(quic_initial_window, config.quic_initial_window);
+        #[cfg(feature = "quic")]
+        overlay!(
+            congestion_control,
+            config.congestion.as_deref().map(parse_congestion_control)
+        );
+        #[cfg(feature = "quic")]
+        overlay!(quic_alpn, config.quic_alpn.clone());
+
+        Ok(opt)
+    }
+
+    /// Builds the server's user registry from `-w`/`--password-hash` (inserted as
+    /// `"default"` for backwards compatibility), repeated `--user
+    /// name:password` entries, and an optional `--users <file>` of the
+    /// same, so the accept path can attribute each connection to a user
+    /// instead of comparing against a single password hash.
+    #[cfg(feature = "server")]
+    pub fn build_user_db(&self) -> Result<UserDb> {
+        let mut db = UserDb::new();
+        db.insert_hash((*self.password_hash).clone(), "default".to_owned());
+
+        for entry in &self.users_inline {
+            match user_db::parse_user_entry(entry) {
+                Some((name, password)) => db.insert(&password, name),
+                None => anyhow::bail!("invalid --user entry, expected name:password: {}", entry),
+            }
+        }
+
+        if let Some(path) = &self.users_file {
+            user_db::load_users_file(&mut db, path)
+                .with_context(|| format!("failed to load --users file {:?}", path))?;
+        }
+
+        Ok(db)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -1,55 +1,101 @@
 use anyhow::{Error, Result};
 use futures::future;
 use std::io::IoSlice;
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
 use tokio::io::*;
 use tokio::net::TcpStream;
 use tracing::*;
-use crate::utils::ParserError;
+use crate::utils::{write_proxy_protocol_header, ParserError, ProxyProtocolMode};
 
 pub struct Target {
     is_https: bool,
+    is_h2: bool,
     host: String,
     port: u16,
     cursor: usize,
+    method: String,
+    /// The original request line and headers, rewritten to origin-form,
+    /// retained verbatim so `send_packet0` can relay it instead of the
+    /// synthesized "GET /" request.
+    request_head: Vec<u8>,
+    /// Body bytes already buffered past the header terminator.
+    body_prefix: Vec<u8>,
+    proxy_protocol_mode: ProxyProtocolMode,
+    client_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
 }
 
-const HEADER0: &'static [u8] = b"GET / HTTP/1.1\r\nHost: ";
-const HEADER1: &'static [u8] = b"\r\nConnection: keep-alive\r\n\r\n";
+/// The HTTP/2 cleartext connection preface a prior-knowledge client sends
+/// before any frame, in lieu of an HTTP/1.x request line.
+const H2_PRIOR_KNOWLEDGE_PREFACE: &'static [u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
 
 impl Target {
     pub fn new() -> Self {
         Self {
             is_https: false,
+            is_h2: false,
             host: String::new(),
             port: 0,
             cursor: 0,
+            method: String::new(),
+            request_head: Vec::new(),
+            body_prefix: Vec::new(),
+            proxy_protocol_mode: ProxyProtocolMode::Disabled,
+            client_addr: None,
+            local_addr: None,
         }
     }
 
+    /// Configures whether (and which version of) a PROXY protocol header
+    /// is written as the first relayed payload bytes (after the trojan
+    /// packet0 framing) in `send_packet0`.
+    pub fn set_proxy_protocol_mode(&mut self, mode: ProxyProtocolMode) {
+        self.proxy_protocol_mode = mode;
+    }
+
+    pub fn is_h2(&self) -> bool {
+        self.is_h2
+    }
+
     fn set_stream_type(&mut self, buf: &Vec<u8>) -> Result<(), ParserError> {
         if buf.len() < 4 {
             return Err(ParserError::Incomplete);
         }
 
-        if &buf[..4] == b"GET " {
-            self.is_https = false;
-            self.cursor = 4;
+        if &buf[..4] == b"PRI " {
+            if buf.len() < H2_PRIOR_KNOWLEDGE_PREFACE.len() {
+                return Err(ParserError::Incomplete);
+            }
+            if &buf[..H2_PRIOR_KNOWLEDGE_PREFACE.len()] != H2_PRIOR_KNOWLEDGE_PREFACE {
+                return Err(ParserError::Invalid);
+            }
+            self.is_h2 = true;
+            self.cursor = H2_PRIOR_KNOWLEDGE_PREFACE.len();
             return Ok(());
         }
 
-        if buf.len() < 8 {
-            return Err(ParserError::Incomplete);
-        }
-
-        if &buf[..8] == b"CONNECT " {
+        if buf.len() >= 8 && &buf[..8] == b"CONNECT " {
             self.is_https = true;
+            self.method = "CONNECT".to_owned();
             self.cursor = 8;
             return Ok(());
         }
 
-        return Err(ParserError::Invalid);
+        // Any other verb (GET, POST, PUT, DELETE, ...) is a plain-HTTP
+        // forward-proxy request; the method is kept so `send_packet0` can
+        // relay the original request line instead of synthesizing "GET /".
+        let end = match buf.iter().position(|&b| b == b' ') {
+            Some(end) => end,
+            None if buf.len() > 16 => return Err(ParserError::Invalid),
+            None => return Err(ParserError::Incomplete),
+        };
+
+        self.method = String::from_utf8(buf[..end].to_vec()).map_err(|_| ParserError::Invalid)?;
+        self.is_https = false;
+        self.cursor = end + 1;
+        Ok(())
     }
 
     fn set_host(&mut self, buf: &Vec<u8>) -> Result<(), ParserError> {
@@ -106,6 +152,10 @@ impl Target {
         self.host =
             String::from_utf8(buf[start..port_idx].to_vec()).map_err(|_| ParserError::Invalid)?;
 
+        // Remember where the request-target starts so the original path and
+        // query string can be recovered once the full request is buffered.
+        self.cursor = end;
+
         return Ok(());
     }
 
@@ -115,31 +165,135 @@ impl Target {
             self.set_stream_type(buf)?;
         }
 
-        trace!("stream is https: {}", self.is_https);
+        trace!("stream is https: {}, is h2: {}", self.is_https, self.is_h2);
 
-        if self.host.len() == 0 {
+        // h2 prior-knowledge carries no request line; its host is instead
+        // derived from the `:authority` pseudo-header of the first HEADERS
+        // frame, once that frame is fully buffered (see `parse_h2_authority`).
+        if self.host.len() == 0 && !self.is_h2 {
             self.set_host(buf)?;
         }
 
         trace!("stream target host: {}", self.host);
 
-        // `integrity` check
-        if &buf[buf.len() - 4..] == b"\r\n\r\n" {
-            trace!("integrity test passed");
-            return Ok(());
+        if self.is_h2 {
+            // Unlike CONNECT, an h2 prior-knowledge stream's preface and
+            // leading frames *are* the request that needs relaying, so
+            // (unlike the CONNECT trim below) `buf` is left to grow and the
+            // whole prefix up to and including the first HEADERS frame is
+            // retained as `request_head`.
+            return self.parse_h2_authority(buf);
         }
 
-        for i in 0..4 {
-            buf[i] = buf[buf.len() - 4 + i];
+        // CONNECT streams don't need their head retained verbatim, so we
+        // keep the original memory-saving trick of only retaining the
+        // trailing 4 bytes while scanning for the header terminator.
+        if self.is_https {
+            if &buf[buf.len() - 4..] == b"\r\n\r\n" {
+                trace!("integrity test passed");
+                return Ok(());
+            }
+
+            for i in 0..4 {
+                buf[i] = buf[buf.len() - 4 + i];
+            }
+
+            unsafe {
+                buf.set_len(4);
+            }
+            return Err(ParserError::Incomplete);
         }
 
-        unsafe {
-            buf.set_len(4);
+        // Otherwise we need the full request head (and any body bytes that
+        // arrived in the same read) to relay the original request verbatim,
+        // so `buf` is left to grow until the terminator is found.
+        match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(pos) => {
+                trace!("integrity test passed");
+                self.request_head = buf[..pos + 4].to_vec();
+                self.body_prefix = buf[pos + 4..].to_vec();
+                self.rewrite_request_head();
+                Ok(())
+            }
+            None => Err(ParserError::Incomplete),
         }
-        Err(ParserError::Incomplete)
+    }
+
+    /// Walks complete HTTP/2 frames following the connection preface
+    /// (`self.cursor` already points past it) until the first HEADERS frame
+    /// is fully buffered, deriving `self.host`/`self.port` from its
+    /// `:authority` pseudo-header. Leading frames that aren't HEADERS (e.g.
+    /// the client's initial SETTINGS frame) are skipped over but still kept
+    /// in `request_head`, since they must reach the origin verbatim too.
+    ///
+    /// CONTINUATION frames (a HEADERS block split across frames) aren't
+    /// supported, matching the single-frame HEADERS prior-knowledge clients
+    /// are observed to send for their first request.
+    fn parse_h2_authority(&mut self, buf: &mut Vec<u8>) -> Result<(), ParserError> {
+        const FRAME_HEADER_LEN: usize = 9;
+        const FRAME_TYPE_HEADERS: u8 = 0x1;
+        const FLAG_END_HEADERS: u8 = 0x4;
+
+        let mut pos = self.cursor;
+        loop {
+            if buf.len() < pos + FRAME_HEADER_LEN {
+                return Err(ParserError::Incomplete);
+            }
+            let len = ((buf[pos] as usize) << 16)
+                | ((buf[pos + 1] as usize) << 8)
+                | buf[pos + 2] as usize;
+            let frame_type = buf[pos + 3];
+            let flags = buf[pos + 4];
+            let frame_end = pos + FRAME_HEADER_LEN + len;
+            if buf.len() < frame_end {
+                return Err(ParserError::Incomplete);
+            }
+
+            if frame_type == FRAME_TYPE_HEADERS {
+                if flags & FLAG_END_HEADERS == 0 {
+                    return Err(ParserError::Invalid);
+                }
+                let header_block = h2_header_block(&buf[pos + FRAME_HEADER_LEN..frame_end], flags)
+                    .ok_or(ParserError::Invalid)?;
+                let (host, port) =
+                    hpack_find_authority(header_block).ok_or(ParserError::Invalid)?;
+                self.host = host;
+                self.port = port;
+                self.request_head = buf[..frame_end].to_vec();
+                self.body_prefix = buf[frame_end..].to_vec();
+                return Ok(());
+            }
+
+            pos = frame_end;
+        }
+    }
+
+    /// Rewrites the captured request line's (possibly absolute-form)
+    /// request-target to origin-form, leaving every header line untouched.
+    fn rewrite_request_head(&mut self) {
+        let path_start = self.cursor;
+        let mut path_end = path_start;
+        while path_end < self.request_head.len() && self.request_head[path_end] != b' ' {
+            path_end += 1;
+        }
+        let origin_form_path: &[u8] = if path_start == path_end {
+            b"/"
+        } else {
+            &self.request_head[path_start..path_end]
+        };
+
+        let mut rewritten = Vec::with_capacity(self.request_head.len());
+        rewritten.extend_from_slice(self.method.as_bytes());
+        rewritten.push(b' ');
+        rewritten.extend_from_slice(origin_form_path);
+        rewritten.extend_from_slice(&self.request_head[path_end..]);
+        self.request_head = rewritten;
     }
 
     pub async fn accept(&mut self, inbound: &mut TcpStream) -> Result<()> {
+        self.client_addr = inbound.peer_addr().ok();
+        self.local_addr = inbound.local_addr().ok();
+
         let mut buffer = Vec::with_capacity(200);
         loop {
             let read = inbound.read_buf(&mut buffer).await?;
@@ -187,11 +341,30 @@ impl Target {
             .await
             .map_err(|e| Box::new(e))?;
 
+        // The PROXY header is relayed payload, not part of the trojan
+        // packet0 framing: it must follow password_hash/cmd/addr or the
+        // server reads it as (part of) the user hash.
+        if let (Some(client_addr), Some(local_addr)) = (self.client_addr, self.local_addr) {
+            write_proxy_protocol_header(
+                writer.as_mut().get_mut(),
+                self.proxy_protocol_mode,
+                client_addr,
+                local_addr,
+            )
+            .await
+            .map_err(|e| Box::new(e))?;
+        }
+
         if !self.is_https {
+            // Relay the client's original bytes verbatim instead of
+            // synthesizing a bare "GET /" request: for plain HTTP that's
+            // the request line/headers/body prefix; for h2 prior-knowledge
+            // it's the connection preface through the first HEADERS frame
+            // captured by `parse_h2_authority`, which must reach the origin
+            // byte-for-byte or the h2 framing is corrupted.
             let bufs = [
-                IoSlice::new(HEADER0),
-                IoSlice::new(self.host.as_bytes()),
-                IoSlice::new(HEADER1),
+                IoSlice::new(&self.request_head),
+                IoSlice::new(&self.body_prefix),
             ];
 
             future::poll_fn(|cx| writer.as_mut().poll_write_vectored(cx, &bufs[..]))
@@ -206,3 +379,259 @@ impl Target {
         Ok(())
     }
 }
+
+/// Strips a HEADERS frame payload's optional `Pad Length` (PADDED flag) and
+/// `Stream Dependency`/`Weight` (PRIORITY flag) fields, returning the
+/// remaining HPACK header block.
+fn h2_header_block(payload: &[u8], flags: u8) -> Option<&[u8]> {
+    const FLAG_PADDED: u8 = 0x8;
+    const FLAG_PRIORITY: u8 = 0x20;
+
+    let mut start = 0;
+    let mut end = payload.len();
+    if flags & FLAG_PADDED != 0 {
+        let pad_len = *payload.first()? as usize;
+        start += 1;
+        end = end.checked_sub(pad_len)?;
+    }
+    if flags & FLAG_PRIORITY != 0 {
+        end.checked_sub(start)?.checked_sub(5)?;
+        start += 5;
+    }
+    if start > end {
+        return None;
+    }
+    Some(&payload[start..end])
+}
+
+/// Static table index of the `:authority` pseudo-header (RFC 7541
+/// Appendix A). It's always carried as a literal (the value is
+/// per-request), so we only need to recognize this one entry.
+const HPACK_STATIC_AUTHORITY_INDEX: usize = 1;
+
+/// Walks an HPACK header block looking for `:authority`, returning the
+/// `(host, port)` it names. Only literal header field representations are
+/// interpreted (an indexed `:authority` can't occur: its static-table value
+/// is empty, and the dynamic table is necessarily empty this early in the
+/// connection).
+fn hpack_find_authority(block: &[u8]) -> Option<(String, u16)> {
+    let mut i = 0;
+    while i < block.len() {
+        let b = block[i];
+        if b & 0x80 != 0 {
+            // Indexed Header Field: just an index, no value to read.
+            let (_, consumed) = hpack_int(block, i, 7)?;
+            i += consumed;
+        } else if b & 0xE0 == 0x20 {
+            // Dynamic Table Size Update.
+            let (_, consumed) = hpack_int(block, i, 5)?;
+            i += consumed;
+        } else {
+            // Literal Header Field, with or without/never indexing; the
+            // name-index prefix is 6 bits for the former, 4 for the latter.
+            let prefix_bits = if b & 0xC0 == 0x40 { 6 } else { 4 };
+            let (next, authority) = hpack_literal_field(block, i, prefix_bits)?;
+            if let Some(authority) = authority {
+                return parse_authority(&authority);
+            }
+            i = next;
+        }
+    }
+    None
+}
+
+/// Parses one literal header field (index-name or literal-name, with or
+/// without indexing) starting at `block[pos]`, whose name-index prefix is
+/// `prefix_bits` wide. Returns the offset just past the field, plus the
+/// field's value if its name is `:authority`.
+fn hpack_literal_field(
+    block: &[u8],
+    pos: usize,
+    prefix_bits: u32,
+) -> Option<(usize, Option<String>)> {
+    let (name_index, consumed) = hpack_int(block, pos, prefix_bits)?;
+    let mut i = pos + consumed;
+    let is_authority = if name_index == 0 {
+        let (name, consumed) = hpack_string(block, i)?;
+        i += consumed;
+        name == ":authority"
+    } else {
+        name_index == HPACK_STATIC_AUTHORITY_INDEX
+    };
+    let (value, consumed) = hpack_string(block, i)?;
+    i += consumed;
+    Some((i, if is_authority { Some(value) } else { None }))
+}
+
+/// Reads an RFC 7541 §5.1 integer whose prefix occupies the low
+/// `prefix_bits` bits of `block[pos]`. Returns `(value, bytes_consumed)`.
+fn hpack_int(block: &[u8], pos: usize, prefix_bits: u32) -> Option<(usize, usize)> {
+    let mask = (1u16 << prefix_bits) as usize - 1;
+    let first = *block.get(pos)? as usize & mask;
+    if first < mask {
+        return Some((first, 1));
+    }
+    let mut value = mask;
+    let mut shift = 0u32;
+    let mut consumed = 1;
+    loop {
+        let byte = *block.get(pos + consumed)? as usize;
+        consumed += 1;
+        value += (byte & 0x7F) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    Some((value, consumed))
+}
+
+/// Reads an RFC 7541 §5.2 string literal (7-bit length prefix + Huffman
+/// flag) at `block[pos]`. Returns `(value, bytes_consumed)`.
+fn hpack_string(block: &[u8], pos: usize) -> Option<(String, usize)> {
+    let huffman = block.get(pos)? & 0x80 != 0;
+    let (len, len_consumed) = hpack_int(block, pos, 7)?;
+    let start = pos + len_consumed;
+    let end = start.checked_add(len)?;
+    let raw = block.get(start..end)?;
+    let value = if huffman {
+        huffman_decode(raw)?
+    } else {
+        String::from_utf8(raw.to_vec()).ok()?
+    };
+    Some((value, len_consumed + len))
+}
+
+/// Splits a literal `:authority` value into `(host, port)`, defaulting to
+/// port 80 when no `:port` suffix is present. This parser only ever sees
+/// h2 *prior-knowledge* (cleartext h2c) requests -- the `PRI * HTTP/2.0`
+/// preface the local proxy is wired to detect -- whose origin default is
+/// 80, not 443, so a portless authority must not be sent to the wrong port.
+fn parse_authority(authority: &str) -> Option<(String, u16)> {
+    match authority.rsplit_once(':') {
+        Some((host, port)) => Some((host.to_owned(), port.parse().ok()?)),
+        None => Some((authority.to_owned(), 80)),
+    }
+}
+
+/// Canonical HPACK Huffman code table (RFC 7541 Appendix B), restricted to
+/// the printable-ASCII subset a hostname/`:authority` value can actually
+/// contain. Any other symbol fails the decode rather than risk silently
+/// mis-decoding bits with a code this table doesn't recognize.
+const HUFFMAN_TABLE: &[(u8, char, u32)] = &[
+    (5, '0', 0x00),
+    (5, '1', 0x01),
+    (5, '2', 0x02),
+    (5, 'a', 0x03),
+    (5, 'c', 0x04),
+    (5, 'e', 0x05),
+    (5, 'i', 0x06),
+    (5, 'o', 0x07),
+    (5, 's', 0x08),
+    (5, 't', 0x09),
+    (6, ' ', 0x14),
+    (6, '%', 0x15),
+    (6, '-', 0x16),
+    (6, '.', 0x17),
+    (6, '/', 0x18),
+    (6, '3', 0x19),
+    (6, '4', 0x1a),
+    (6, '5', 0x1b),
+    (6, '6', 0x1c),
+    (6, '7', 0x1d),
+    (6, '8', 0x1e),
+    (6, '9', 0x1f),
+    (6, '=', 0x20),
+    (6, 'A', 0x21),
+    (6, '_', 0x22),
+    (6, 'b', 0x23),
+    (6, 'd', 0x24),
+    (6, 'f', 0x25),
+    (6, 'g', 0x26),
+    (6, 'h', 0x27),
+    (6, 'l', 0x28),
+    (6, 'm', 0x29),
+    (6, 'n', 0x2a),
+    (6, 'p', 0x2b),
+    (6, 'r', 0x2c),
+    (6, 'u', 0x2d),
+    (7, ':', 0x5c),
+    (7, 'B', 0x5d),
+    (7, 'C', 0x5e),
+    (7, 'D', 0x5f),
+    (7, 'E', 0x60),
+    (7, 'F', 0x61),
+    (7, 'G', 0x62),
+    (7, 'H', 0x63),
+    (7, 'I', 0x64),
+    (7, 'J', 0x65),
+    (7, 'K', 0x66),
+    (7, 'L', 0x67),
+    (7, 'M', 0x68),
+    (7, 'N', 0x69),
+    (7, 'O', 0x6a),
+    (7, 'P', 0x6b),
+    (7, 'Q', 0x6c),
+    (7, 'R', 0x6d),
+    (7, 'S', 0x6e),
+    (7, 'T', 0x6f),
+    (7, 'U', 0x70),
+    (7, 'V', 0x71),
+    (7, 'W', 0x72),
+    (7, 'Y', 0x73),
+    (7, 'j', 0x74),
+    (7, 'k', 0x75),
+    (7, 'q', 0x76),
+    (7, 'v', 0x77),
+    (7, 'w', 0x78),
+    (7, 'x', 0x79),
+    (7, 'y', 0x7a),
+    (7, 'z', 0x7b),
+    (8, '&', 0xf8),
+    (8, '*', 0xf9),
+    (8, ',', 0xfa),
+    (8, ';', 0xfb),
+    (8, 'X', 0xfc),
+    (8, 'Z', 0xfd),
+];
+
+/// Decodes an HPACK-Huffman-coded byte string using [`HUFFMAN_TABLE`].
+/// Returns `None` if a symbol outside that table is encountered, or the
+/// bit stream doesn't end in valid EOS padding.
+fn huffman_decode(data: &[u8]) -> Option<String> {
+    let mut bits = Vec::with_capacity(data.len() * 8);
+    for &byte in data {
+        for i in (0..8).rev() {
+            bits.push((byte >> i) & 1 == 1);
+        }
+    }
+
+    let mut out = String::new();
+    let mut pos = 0;
+    while pos < bits.len() {
+        if bits[pos..].iter().all(|&b| b) && bits.len() - pos < 8 {
+            // Trailing EOS padding (all 1-bits, shorter than any real code).
+            break;
+        }
+        let mut matched = false;
+        for &(len, sym, code) in HUFFMAN_TABLE {
+            let len = len as usize;
+            if pos + len > bits.len() {
+                continue;
+            }
+            let value = bits[pos..pos + len]
+                .iter()
+                .fold(0u32, |acc, &b| (acc << 1) | b as u32);
+            if value == code {
+                out.push(sym);
+                pos += len;
+                matched = true;
+                break;
+            }
+        }
+        if !matched {
+            return None;
+        }
+    }
+    Some(out)
+}
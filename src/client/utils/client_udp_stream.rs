@@ -2,17 +2,43 @@ use crate::utils::{
     CursoredBuffer, ExtendableFromSlice, MixAddrType, UdpRead, UdpRelayBuffer, UdpWrite,
 };
 use futures::ready;
+use std::collections::HashMap;
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use std::time::Duration;
 use std::{
     net::SocketAddr,
     ops::{Deref, DerefMut},
 };
-use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::UdpSocket;
-use tokio::sync::oneshot;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
 use tracing::{debug, warn};
 
+/// RFC 1928 recommends ~5 seconds before an incomplete fragment sequence is
+/// abandoned.
+const FRAGMENT_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a NAT-style UDP association may sit idle before it's reclaimed.
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Default cap on concurrent associations multiplexed over one bound
+/// socket, used unless [`Socks5UdpStream::with_max_associations`] overrides
+/// it.
+const DEFAULT_MAX_ASSOCIATIONS: usize = 256;
+
+/// Accumulates the payloads of a SOCKS5 UDP fragment sequence (FRAG != 0)
+/// until the end-of-sequence fragment (FRAG & 0x80) arrives.
+#[derive(Debug)]
+struct FragmentQueue {
+    addr: MixAddrType,
+    /// Sequence position (low 7 bits of FRAG) of the last fragment folded in.
+    seq: u8,
+    payload: Vec<u8>,
+    started_at: Instant,
+}
+
 #[derive(Debug)]
 struct Socks5UdpSpecifiedBuffer {
     inner: Vec<u8>,
@@ -63,170 +89,142 @@ impl DerefMut for Socks5UdpSpecifiedBuffer {
     }
 }
 
-pub struct Socks5UdpStream {
-    server_udp_socket: UdpSocket,
-    client_udp_addr: Option<SocketAddr>,
-    signal_reset: oneshot::Receiver<()>,
-}
-
+/// NAT-style table entry tracking one peer's association.
 #[derive(Debug)]
-pub struct Socks5UdpRecvStream<'a> {
-    server_udp_socket: &'a UdpSocket,
-    client_udp_addr: Option<SocketAddr>,
-    addr_tx: Option<oneshot::Sender<SocketAddr>>,
-    signal_reset: &'a mut oneshot::Receiver<()>,
+struct Session {
+    /// Feeds datagrams received from this peer to its relay task.
+    inbound_tx: mpsc::Sender<Vec<u8>>,
+    last_seen: Instant,
 }
 
-impl<'a> Socks5UdpRecvStream<'a> {
-    fn new(
-        server_udp_socket: &'a UdpSocket,
-        addr_tx: oneshot::Sender<SocketAddr>,
-        signal_reset: &'a mut oneshot::Receiver<()>,
-    ) -> Self {
-        Self {
-            server_udp_socket,
-            client_udp_addr: None,
-            addr_tx: Some(addr_tx),
-            signal_reset,
+fn reap_idle_sessions(sessions: &mut HashMap<SocketAddr, Session>) {
+    sessions.retain(|peer, session| {
+        let alive = session.last_seen.elapsed() < SESSION_IDLE_TIMEOUT;
+        if !alive {
+            debug!("socks5 udp: evicting idle association with {}", peer);
         }
-    }
+        alive
+    });
 }
 
-#[derive(Debug)]
-pub struct Socks5UdpSendStream<'a> {
-    server_udp_socket: &'a UdpSocket,
-    client_udp_addr: Option<SocketAddr>,
-    addr_rx: Option<oneshot::Receiver<SocketAddr>>,
-    buffer: Socks5UdpSpecifiedBuffer,
+/// Demultiplexes many SOCKS5 UDP associations over a single bound
+/// `UdpSocket`, keyed by source [`SocketAddr`], rather than requiring one
+/// socket per client. Call [`accept`](Socks5UdpStream::accept) in a loop;
+/// each call either routes a datagram to an already-known peer's running
+/// association, or returns a fresh [`Socks5UdpAssociation`] the first time a
+/// peer is seen, for the caller to spawn a relay task (e.g. `copy_udp`) on.
+pub struct Socks5UdpStream {
+    socket: Arc<UdpSocket>,
+    sessions: Arc<Mutex<HashMap<SocketAddr, Session>>>,
+    max_associations: usize,
+    signal_reset: oneshot::Receiver<()>,
 }
 
-impl<'a> Socks5UdpSendStream<'a> {
-    fn new(server_udp_socket: &'a UdpSocket, addr_tx: oneshot::Receiver<SocketAddr>) -> Self {
-        Self {
+impl Socks5UdpStream {
+    pub fn new(server_udp_socket: UdpSocket, stream_reset_signal_rx: oneshot::Receiver<()>) -> Self {
+        Self::with_max_associations(
             server_udp_socket,
-            client_udp_addr: None,
-            addr_rx: Some(addr_tx),
-            buffer: Socks5UdpSpecifiedBuffer::new(2048),
-        }
+            stream_reset_signal_rx,
+            DEFAULT_MAX_ASSOCIATIONS,
+        )
     }
-}
 
-impl Socks5UdpStream {
-    pub fn new(
+    pub fn with_max_associations(
         server_udp_socket: UdpSocket,
         stream_reset_signal_rx: oneshot::Receiver<()>,
+        max_associations: usize,
     ) -> Self {
         Self {
-            server_udp_socket,
-            client_udp_addr: None,
+            socket: Arc::new(server_udp_socket),
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            max_associations,
             signal_reset: stream_reset_signal_rx,
         }
     }
 
-    pub fn split<'a>(&'a mut self) -> (Socks5UdpSendStream<'a>, Socks5UdpRecvStream<'a>) {
-        let (addr_tx, addr_rx) = oneshot::channel();
-        (
-            Socks5UdpSendStream::new(&self.server_udp_socket, addr_rx),
-            Socks5UdpRecvStream::new(&self.server_udp_socket, addr_tx, &mut self.signal_reset),
-        )
-    }
-}
-
-impl<'a> AsyncRead for Socks5UdpRecvStream<'a> {
-    fn poll_read(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-        buf: &mut tokio::io::ReadBuf<'_>,
-    ) -> std::task::Poll<std::io::Result<()>> {
-        let addr = match ready!(self.server_udp_socket.poll_recv_from(cx, buf)) {
-            Ok(addr) => addr,
-            Err(e) => {
-                return Poll::Ready(Err(e));
+    /// Reads datagrams off the shared socket until a peer not already
+    /// associated is seen, returning its dedicated
+    /// [`Socks5UdpAssociation`]. Datagrams from peers with a running
+    /// association are fed straight into that association's channel instead
+    /// of being surfaced here.
+    pub async fn accept(&mut self) -> std::io::Result<Socks5UdpAssociation> {
+        let mut recv_buf = vec![0u8; 65536];
+        loop {
+            crate::try_recv!(
+                oneshot,
+                self.signal_reset,
+                return Err(std::io::ErrorKind::Other.into())
+            );
+
+            let (n, peer) = self.socket.recv_from(&mut recv_buf).await?;
+            let datagram = recv_buf[..n].to_vec();
+
+            let mut sessions = self.sessions.lock().unwrap();
+            reap_idle_sessions(&mut sessions);
+
+            if let Some(session) = sessions.get_mut(&peer) {
+                session.last_seen = Instant::now();
+                // The relay task may have exited (e.g. upstream closed); a
+                // full channel or a dead receiver just drops this datagram,
+                // same as a dropped UDP packet on the wire.
+                let _ = session.inbound_tx.try_send(datagram);
+                continue;
             }
-        };
 
-        if self.client_udp_addr.is_none() {
-            self.client_udp_addr = Some(addr.clone());
-            let addr_tx = match self.addr_tx.take() {
-                Some(v) => v,
-                None => {
-                    return Poll::Ready(Err(std::io::ErrorKind::Other.into()));
-                }
-            };
-            match addr_tx.send(addr) {
-                Ok(_) => {
-                    return Poll::Ready(Ok(()));
-                }
-                Err(_) => {
-                    return Poll::Ready(Err(std::io::ErrorKind::Other.into()));
-                }
-            }
-        } else {
-            if self.client_udp_addr.unwrap() != addr {
-                return Poll::Ready(Err(std::io::ErrorKind::Interrupted.into()));
+            if sessions.len() >= self.max_associations {
+                warn!(
+                    "socks5 udp: refusing new association from {}, {} already active",
+                    peer,
+                    sessions.len()
+                );
+                continue;
             }
-        }
-        Poll::Ready(Ok(()))
-    }
-}
 
-impl<'a> Socks5UdpSendStream<'a> {
-    fn poll_write_optioned(
-        self: &mut std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-        buf: Option<&[u8]>,
-    ) -> Poll<Result<usize, std::io::Error>> {
-        if self.client_udp_addr.is_none() {
-            let maybe_addr = match self.addr_rx {
-                Some(ref mut rx) => rx.try_recv(),
-                None => {
-                    return Poll::Ready(Err(std::io::ErrorKind::Other.into()));
-                }
-            };
-
-            self.client_udp_addr = match maybe_addr {
-                Ok(addr) => Some(addr),
-                Err(_) => {
-                    return Poll::Ready(Err(std::io::ErrorKind::WouldBlock.into()));
-                }
-            }
+            let (inbound_tx, inbound_rx) = mpsc::channel(32);
+            let _ = inbound_tx.try_send(datagram);
+            sessions.insert(
+                peer,
+                Session {
+                    inbound_tx,
+                    last_seen: Instant::now(),
+                },
+            );
+            drop(sessions);
+
+            return Ok(Socks5UdpAssociation {
+                peer_addr: peer,
+                socket: self.socket.clone(),
+                sessions: self.sessions.clone(),
+                inbound_rx,
+                reassembly: None,
+                send_buffer: Socks5UdpSpecifiedBuffer::new(2048),
+            });
         }
-
-        let buf = match buf {
-            Some(b) => b,
-            None => &self.buffer,
-        };
-
-        self.server_udp_socket
-            .poll_send_to(cx, buf, self.client_udp_addr.unwrap())
     }
 }
 
-impl<'a> AsyncWrite for Socks5UdpSendStream<'a> {
-    fn poll_write(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-        buf: &[u8],
-    ) -> Poll<Result<usize, std::io::Error>> {
-        (&mut self).poll_write_optioned(cx, Some(buf))
-    }
-
-    fn poll_flush(
-        self: std::pin::Pin<&mut Self>,
-        _: &mut std::task::Context<'_>,
-    ) -> Poll<Result<(), std::io::Error>> {
-        Poll::Ready(Ok(()))
-    }
+/// One peer's slice of a [`Socks5UdpStream`]'s demultiplexed socket:
+/// receives datagrams already routed to it (reassembling SOCKS5 UDP
+/// fragments per RFC 1928) and sends replies back to that same peer with
+/// `poll_send_to`. Implements [`UdpRead`]/[`UdpWrite`] so it drops straight
+/// into `copy_udp` like any other proxy stream half.
+#[derive(Debug)]
+pub struct Socks5UdpAssociation {
+    peer_addr: SocketAddr,
+    socket: Arc<UdpSocket>,
+    sessions: Arc<Mutex<HashMap<SocketAddr, Session>>>,
+    inbound_rx: mpsc::Receiver<Vec<u8>>,
+    reassembly: Option<FragmentQueue>,
+    send_buffer: Socks5UdpSpecifiedBuffer,
+}
 
-    fn poll_shutdown(
-        self: std::pin::Pin<&mut Self>,
-        _: &mut std::task::Context<'_>,
-    ) -> Poll<Result<(), std::io::Error>> {
-        Poll::Ready(Ok(()))
+impl Socks5UdpAssociation {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
     }
 }
 
-impl<'a> UdpRead for Socks5UdpRecvStream<'a> {
+impl UdpRead for Socks5UdpAssociation {
     /// ```not_rust
     /// +----+------+------+----------+----------+----------+
     /// |RSV | FRAG | ATYP | DST.ADDR | DST.PORT |   DATA   |
@@ -249,71 +247,121 @@ impl<'a> UdpRead for Socks5UdpRecvStream<'a> {
         cx: &mut Context<'_>,
         buf: &mut UdpRelayBuffer,
     ) -> Poll<std::io::Result<MixAddrType>> {
-        debug!("Socks5UdpRecvStream::poll_proxy_stream_read()");
-        let mut buf_inner = buf.as_read_buf();
-        let ptr = buf_inner.filled().as_ptr();
-
-        crate::try_recv!(
-            oneshot,
-            self.signal_reset,
-            return Poll::Ready(Ok(MixAddrType::None))
-        );
+        debug!("Socks5UdpAssociation::poll_proxy_stream_read()");
+        loop {
+            let datagram = match ready!(self.inbound_rx.poll_recv(cx)) {
+                Some(datagram) => datagram,
+                // Sender dropped: the association was evicted for being
+                // idle, so there's nothing left to relay.
+                None => return Poll::Ready(Ok(MixAddrType::None)),
+            };
 
-        match ready!(self.poll_read(cx, &mut buf_inner)) {
-            Ok(_) => {
-                // Ensure the pointer does not change from under us
-                assert_eq!(ptr, buf_inner.filled().as_ptr());
-                let n = buf_inner.filled().len();
+            if datagram.len() < 3 {
+                debug!("socks5 udp: dropping undersized datagram from a peer");
+                continue;
+            }
+
+            let frag = datagram[2];
+            buf.clear();
+            buf.replace_tail(&datagram[3..]);
 
-                if n < 3 {
-                    return Poll::Ready(Ok(MixAddrType::None));
+            let addr = match MixAddrType::from_encoded(buf) {
+                Ok(addr) => addr,
+                Err(_) => {
+                    debug!("socks5 udp: dropping malformed datagram from a peer");
+                    continue;
                 }
+            };
+
+            if frag == 0 {
+                // Standalone datagram; any in-flight reassembly is moot.
+                self.reassembly = None;
+                return Poll::Ready(Ok(addr));
+            }
+
+            let seq = frag & 0x7F;
+            let is_last = frag & 0x80 != 0;
 
-                // Safety: This is guaranteed to be the number of initialized (and read)
-                // bytes due to the invariants provided by `ReadBuf::filled`.
-                unsafe {
-                    buf.advance_mut(n);
+            if let Some(queue) = &self.reassembly {
+                if queue.started_at.elapsed() >= FRAGMENT_REASSEMBLY_TIMEOUT {
+                    debug!("socks5 udp fragment reassembly timed out, dropping it");
+                    self.reassembly = None;
                 }
-                buf.advance(3);
+            }
+
+            let in_order = match &self.reassembly {
+                Some(queue) => seq == queue.seq + 1,
+                None => seq == 1,
+            };
+            if !in_order {
                 debug!(
-                    "Socks5UdpRecvStream::poll_proxy_stream_read() buf {:?}",
-                    buf
+                    "socks5 udp fragment out of order (frag {:#x}), discarding partial queue",
+                    frag
                 );
-                Poll::Ready(
-                    MixAddrType::from_encoded(buf).map_err(|_| std::io::ErrorKind::Other.into()),
-                )
+                self.reassembly = None;
+                if seq != 1 {
+                    // Not a valid sequence start either; wait for one.
+                    continue;
+                }
+            }
+
+            if self.reassembly.is_none() {
+                self.reassembly = Some(FragmentQueue {
+                    addr,
+                    seq: 0,
+                    payload: Vec::new(),
+                    started_at: Instant::now(),
+                });
+            }
+            let queue = self.reassembly.as_mut().unwrap();
+            queue.payload.extend_from_slice(buf.as_bytes());
+            queue.seq = seq;
+
+            if !is_last {
+                continue;
             }
-            Err(e) => Poll::Ready(Err(e)),
+
+            let queue = self.reassembly.take().unwrap();
+            buf.clear();
+            buf.replace_tail(&queue.payload);
+            return Poll::Ready(Ok(queue.addr));
         }
     }
 }
 
-impl<'a> UdpWrite for Socks5UdpSendStream<'a> {
+impl UdpWrite for Socks5UdpAssociation {
     fn poll_proxy_stream_write(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &[u8],
         addr: &MixAddrType,
     ) -> Poll<std::io::Result<usize>> {
-        let just_filled_buf = if self.buffer.is_empty() {
-            addr.write_buf(&mut self.buffer);
-            self.buffer.extend_from_slice(buf);
+        let just_filled_buf = if self.send_buffer.is_empty() {
+            addr.write_buf(&mut self.send_buffer);
+            self.send_buffer.extend_from_slice(buf);
             true
         } else {
             false
         };
 
-        // only if we write the whole buf in one write we reset the buffer
-        // to accept new data.
-        match self.poll_write_optioned(cx, None)? {
+        // Downlink replies go out over this association's own socket, not
+        // through `Socks5UdpStream::accept`'s uplink path, so `last_seen`
+        // needs refreshing here too or a quiet uplink with a busy download
+        // gets reaped mid-transfer.
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(&self.peer_addr) {
+            session.last_seen = Instant::now();
+        }
+
+        let peer_addr = self.peer_addr;
+        match self.socket.poll_send_to(cx, &self.send_buffer, peer_addr)? {
             Poll::Ready(real_written_amt) => {
-                if real_written_amt == self.buffer.len() {
-                    self.buffer.reset();
+                if real_written_amt == self.send_buffer.len() {
+                    self.send_buffer.reset();
                 } else {
-                    warn!("Socks5UdpSendStream didn't send the entire buffer");
+                    warn!("Socks5UdpAssociation didn't send the entire buffer");
                 }
             }
-            _ => (),
+            Poll::Pending => (),
         }
 
         if just_filled_buf {
@@ -323,11 +371,11 @@ impl<'a> UdpWrite for Socks5UdpSendStream<'a> {
         }
     }
 
-    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
-        AsyncWrite::poll_flush(self, cx)
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
     }
 
-    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
         todo!()
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,270 @@
+//! Client side of the WebSocket carrier transport: tunnels the trojan
+//! payload inside `Upgrade: websocket` binary frames so the connection
+//! blends with ordinary traffic behind CDNs that only forward HTTP(S).
+//! Frame (de)serialization lives in [`crate::utils::ws_codec`], shared
+//! with [`crate::server::utils::ws_stream::WsStream`] on the other end;
+//! this module only owns the handshake and the `AsyncRead`/`AsyncWrite`
+//! adapter.
+
+use std::fmt::Debug;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use anyhow::{bail, Result};
+use base64;
+use rand::RngCore;
+use sha1::{Digest, Sha1};
+use tokio::io::{
+    split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf,
+};
+use tracing::debug;
+
+use crate::{
+    server::inbound::stream_trait::Splitable,
+    utils::ws_codec::{encode_frame, parse_frame, WsFrame, OPCODE_BINARY, OPCODE_PONG},
+};
+
+const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+fn sec_websocket_accept(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(WS_GUID.as_bytes());
+    base64::encode(hasher.finalize())
+}
+
+/// Wraps a stream so trojan bytes are carried as masked WebSocket binary
+/// frames on write, and unframed transparently on read (the server's frames
+/// arrive unmasked).
+pub struct WsStream<S> {
+    inner: S,
+    recv_raw: Vec<u8>,
+    recv_payload: Vec<u8>,
+    /// Bytes from `Data { fin: false, .. }` frames seen so far, pending the
+    /// final fragment of the current message.
+    fragment_buf: Vec<u8>,
+    /// Encoded pong replies queued by `poll_read`, flushed ahead of
+    /// application data the next time `poll_write` runs.
+    pending_ctrl: Vec<u8>,
+    send_buf: Vec<u8>,
+    send_data_len: usize,
+}
+
+impl<S: Debug> Debug for WsStream<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WsStream").field("inner", &self.inner).finish()
+    }
+}
+
+impl<S> WsStream<S> {
+    fn new(inner: S) -> Self {
+        Self {
+            inner,
+            recv_raw: Vec::new(),
+            recv_payload: Vec::new(),
+            fragment_buf: Vec::new(),
+            pending_ctrl: Vec::new(),
+            send_buf: Vec::new(),
+            send_data_len: 0,
+        }
+    }
+}
+
+impl<S> WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Performs the client-side `Upgrade: websocket` handshake on `stream`
+    /// and returns an adapter that carries the trojan payload inside
+    /// binary frames.
+    pub async fn client_handshake(mut stream: S, host: &str, path: &str) -> Result<Self> {
+        let mut key_bytes = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut key_bytes);
+        let key = base64::encode(key_bytes);
+
+        let request = format!(
+            "GET {} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Key: {}\r\n\
+             Sec-WebSocket-Version: 13\r\n\r\n",
+            path, host, key
+        );
+        stream.write_all(request.as_bytes()).await?;
+        stream.flush().await?;
+
+        let expected_accept = sec_websocket_accept(&key);
+        let mut response = Vec::with_capacity(256);
+        let mut chunk = [0u8; 256];
+        loop {
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                bail!("EOF during websocket handshake");
+            }
+            response.extend_from_slice(&chunk[..n]);
+
+            let head_end = match response.windows(4).position(|w| w == b"\r\n\r\n") {
+                Some(pos) => pos,
+                None => continue,
+            };
+
+            let head = String::from_utf8_lossy(&response[..head_end]).into_owned();
+            debug!("websocket handshake response: {}", head);
+
+            if !head.starts_with("HTTP/1.1 101") {
+                bail!(
+                    "websocket upgrade rejected: {}",
+                    head.lines().next().unwrap_or("")
+                );
+            }
+
+            let accept = head.lines().find_map(|l| {
+                let (name, value) = l.split_once(':')?;
+                if name.trim().eq_ignore_ascii_case("sec-websocket-accept") {
+                    Some(value.trim().to_owned())
+                } else {
+                    None
+                }
+            });
+
+            match accept {
+                Some(v) if v == expected_accept => (),
+                _ => bail!("Sec-WebSocket-Accept mismatch"),
+            }
+
+            let mut ws = WsStream::new(stream);
+            ws.recv_raw = response[head_end + 4..].to_vec();
+            return Ok(ws);
+        }
+    }
+}
+
+impl<S> AsyncRead for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        loop {
+            if !self.recv_payload.is_empty() {
+                let n = std::cmp::min(buf.remaining(), self.recv_payload.len());
+                buf.put_slice(&self.recv_payload[..n]);
+                self.recv_payload.drain(..n);
+                return Poll::Ready(Ok(()));
+            }
+
+            match parse_frame(&self.recv_raw) {
+                Some((frame, consumed)) => {
+                    self.recv_raw.drain(..consumed);
+                    match frame {
+                        WsFrame::Data { payload, fin } => {
+                            if fin {
+                                if self.fragment_buf.is_empty() {
+                                    self.recv_payload = payload;
+                                } else {
+                                    self.fragment_buf.extend_from_slice(&payload);
+                                    self.recv_payload = std::mem::take(&mut self.fragment_buf);
+                                }
+                            } else {
+                                self.fragment_buf.extend_from_slice(&payload);
+                            }
+                        }
+                        WsFrame::Ping(payload) => {
+                            // Best-effort: queued ahead of the next
+                            // application write rather than written here,
+                            // since we only hold a read-path `Context`.
+                            self.pending_ctrl
+                                .extend_from_slice(&encode_frame(OPCODE_PONG, &payload, true));
+                        }
+                        WsFrame::Pong(_) => (),
+                        WsFrame::Close => return Poll::Ready(Ok(())),
+                    }
+                }
+                None => {
+                    let me = &mut *self;
+                    let mut tmp = [0u8; 4096];
+                    let mut read_buf = ReadBuf::new(&mut tmp);
+                    match Pin::new(&mut me.inner).poll_read(cx, &mut read_buf)? {
+                        Poll::Ready(()) => {
+                            if read_buf.filled().is_empty() {
+                                return Poll::Ready(Ok(()));
+                            }
+                            me.recv_raw.extend_from_slice(read_buf.filled());
+                        }
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        if !self.pending_ctrl.is_empty() {
+            let me = &mut *self;
+            match Pin::new(&mut me.inner).poll_write(cx, &me.pending_ctrl)? {
+                Poll::Ready(0) => return Poll::Ready(Ok(0)),
+                Poll::Ready(n) if n < me.pending_ctrl.len() => {
+                    me.pending_ctrl.drain(..n);
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                Poll::Ready(_) => me.pending_ctrl.clear(),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        if self.send_buf.is_empty() {
+            self.send_data_len = buf.len();
+            // Clients must always mask a frame.
+            self.send_buf = encode_frame(OPCODE_BINARY, buf, true);
+        }
+
+        let me = &mut *self;
+        match Pin::new(&mut me.inner).poll_write(cx, &me.send_buf)? {
+            Poll::Ready(0) => Poll::Ready(Ok(0)),
+            Poll::Ready(n) if n < me.send_buf.len() => {
+                me.send_buf.drain(..n);
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            Poll::Ready(_) => {
+                me.send_buf.clear();
+                Poll::Ready(Ok(me.send_data_len))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl<S> Splitable for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Debug + Send + 'static,
+{
+    type R = ReadHalf<WsStream<S>>;
+    type W = WriteHalf<WsStream<S>>;
+
+    fn split(self) -> (Self::R, Self::W) {
+        split(self)
+    }
+}
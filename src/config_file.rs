@@ -0,0 +1,51 @@
+//! Optional `--config <path>` file (TOML or JSON, picked by extension)
+//! mirroring [`Opt`](crate::args::Opt), so a server with many users,
+//! certificate paths and transport knobs can be configured reproducibly
+//! from a single file instead of a long flag list. Every field is optional:
+//! values left out fall back to whatever the CLI (or its structopt
+//! defaults) supplies, and a CLI flag the user actually passed always wins
+//! over the file — see `Opt::load_with_config`.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub struct ConfigFile {
+    pub local_http_addr: Option<String>,
+    pub local_socks5_addr: Option<String>,
+    pub log_level: Option<String>,
+    pub ca: Option<PathBuf>,
+    pub proxy_url: Option<String>,
+    pub proxy_port: Option<String>,
+    pub proxy_ip: Option<String>,
+    pub server: Option<bool>,
+    pub key: Option<PathBuf>,
+    pub cert: Option<PathBuf>,
+    pub password: Option<String>,
+    pub users: Option<Vec<String>>,
+    pub users_file: Option<PathBuf>,
+    pub fallback_port: Option<String>,
+    pub connection_mode: Option<String>,
+    pub proxy_protocol: Option<String>,
+    pub pool_size: Option<usize>,
+    pub quic_idle_timeout: Option<u64>,
+    pub quic_keepalive: Option<u64>,
+    pub quic_max_bidi_streams: Option<u64>,
+    pub quic_initial_window: Option<u64>,
+    pub congestion: Option<String>,
+    pub quic_alpn: Option<String>,
+}
+
+/// Loads a config file, picking TOML or JSON based on its extension
+/// (defaulting to TOML for anything else).
+pub fn load(path: &Path) -> Result<ConfigFile> {
+    let contents =
+        std::fs::read_to_string(path).with_context(|| format!("failed to read {:?}", path))?;
+    if path.extension().map_or(false, |ext| ext == "json") {
+        serde_json::from_str(&contents).with_context(|| format!("failed to parse {:?} as JSON", path))
+    } else {
+        toml::from_str(&contents).with_context(|| format!("failed to parse {:?} as TOML", path))
+    }
+}
@@ -12,6 +12,9 @@ pub enum ServiceMode {
     UDP,
     #[cfg(feature = "mini_tls")]
     MiniTLS,
+    /// Trojan payload carried over RFC 6455 WebSocket binary frames, for
+    /// deployments fronted by a CDN that only forwards HTTP(S).
+    WebSocket,
 }
 
 impl ServiceMode {
@@ -21,6 +24,7 @@ impl ServiceMode {
             ServiceMode::UDP => 0x03,
             #[cfg(feature = "mini_tls")]
             ServiceMode::MiniTLS => 0x11,
+            ServiceMode::WebSocket => 0x13,
         }
     }
 }
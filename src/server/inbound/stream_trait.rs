@@ -10,6 +10,12 @@ use tokio::{
     net::tcp::{OwnedReadHalf, OwnedWriteHalf},
 };
 
+#[cfg(feature = "unix_socket")]
+use tokio::net::{
+    unix::{OwnedReadHalf as UnixOwnedReadHalf, OwnedWriteHalf as UnixOwnedWriteHalf},
+    UnixStream,
+};
+
 use crate::utils::WRTuple;
 
 #[cfg(feature = "quic")]
@@ -82,3 +88,13 @@ impl<'a> Splitable for TcpStream {
         TcpStream::into_split(self)
     }
 }
+
+#[cfg(feature = "unix_socket")]
+impl Splitable for UnixStream {
+    type R = UnixOwnedReadHalf;
+    type W = UnixOwnedWriteHalf;
+
+    fn split(self) -> (Self::R, Self::W) {
+        UnixStream::into_split(self)
+    }
+}
@@ -1,6 +1,5 @@
 use tokio::{
     io::{AsyncRead, AsyncWrite},
-    net::TcpStream,
     sync::broadcast,
 };
 use tracing::{debug, info};
@@ -8,29 +7,41 @@ use tracing::{debug, info};
 use crate::{
     adapt,
     protocol::TCP_MAX_IDLE_TIMEOUT,
+    server::utils::ws_stream::WsStream,
     utils::{
         lite_tls::{LeaveTls, LiteTlsStream},
         Adapter, BufferedRecv, MixAddrType, ParserError, Splitable,
     },
 };
 use anyhow::{anyhow, Context, Result};
+use std::fmt::Debug;
 
 pub enum TcpOption<I> {
     TLS(I),
     LiteTLS(I),
+    /// Trojan payload carried inside WebSocket binary frames; `forward`
+    /// performs the server-side `Upgrade` handshake before relaying.
+    WebSocket(I),
 }
 
 impl<I> TcpOption<BufferedRecv<I>>
 where
-    I: AsyncRead + AsyncWrite + Splitable + LeaveTls + Unpin,
+    I: AsyncRead + AsyncWrite + Splitable + LeaveTls + Unpin + Debug + Send + 'static,
 {
-    pub async fn forward(
+    /// `outbound` is generic over the upstream transport -- a `TcpStream`
+    /// for the common case, but equally a `UnixStream` (fronting a local
+    /// service over a UDS) or any other duplex stream the caller wires up,
+    /// e.g. an in-memory pair in tests.
+    pub async fn forward<O>(
         self,
-        mut outbound: TcpStream,
+        mut outbound: O,
         target_host: &MixAddrType,
         shutdown: broadcast::Receiver<()>,
         conn_id: usize,
-    ) -> Result<()> {
+    ) -> Result<()>
+    where
+        O: AsyncRead + AsyncWrite + Splitable + Unpin + Debug + Send + 'static,
+    {
         use TcpOption::*;
         match self {
             TLS(inbound) => {
@@ -66,6 +77,15 @@ where
                     }
                 }
             }
+            WebSocket(inbound) => {
+                let inbound = WsStream::server_handshake(inbound)
+                    .await
+                    .context("websocket upgrade handshake failed")?;
+                adapt!([ws][conn_id]
+                    inbound[Ws] <=> outbound[Tcp] <=> target_host
+                    Until shutdown Or Sec TCP_MAX_IDLE_TIMEOUT
+                );
+            }
         }
         Ok(())
     }
@@ -0,0 +1,267 @@
+//! Server side of the WebSocket carrier transport: validates the client's
+//! `Upgrade: websocket` request, replies with the RFC 6455 accept
+//! handshake, then relays the trojan stream as binary frames the same way
+//! [`crate::client::utils::ws_stream::WsStream`] does from the other end.
+//! Frame (de)serialization lives in [`crate::utils::ws_codec`]; this module
+//! only owns the handshake and the `AsyncRead`/`AsyncWrite` adapter.
+
+use std::fmt::Debug;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use anyhow::{anyhow, bail, Result};
+use base64;
+use sha1::{Digest, Sha1};
+use tokio::io::{
+    split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf,
+};
+use tracing::debug;
+
+use crate::{
+    server::inbound::stream_trait::Splitable,
+    utils::ws_codec::{encode_frame, parse_frame, WsFrame, OPCODE_BINARY, OPCODE_PONG},
+};
+
+const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+fn sec_websocket_accept(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(WS_GUID.as_bytes());
+    base64::encode(hasher.finalize())
+}
+
+/// Wraps an inbound connection so trojan bytes are carried as unmasked
+/// WebSocket binary frames on write and unframed (unmasking the client's
+/// masked frames) transparently on read.
+pub struct WsStream<S> {
+    inner: S,
+    recv_raw: Vec<u8>,
+    recv_payload: Vec<u8>,
+    /// Bytes from `Data { fin: false, .. }` frames seen so far, pending the
+    /// final fragment of the current message.
+    fragment_buf: Vec<u8>,
+    /// Encoded pong replies queued by `poll_read`, flushed ahead of
+    /// application data the next time `poll_write` runs.
+    pending_ctrl: Vec<u8>,
+    send_buf: Vec<u8>,
+    send_data_len: usize,
+}
+
+impl<S: Debug> Debug for WsStream<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WsStream").field("inner", &self.inner).finish()
+    }
+}
+
+impl<S> WsStream<S> {
+    fn new(inner: S) -> Self {
+        Self {
+            inner,
+            recv_raw: Vec::new(),
+            recv_payload: Vec::new(),
+            fragment_buf: Vec::new(),
+            pending_ctrl: Vec::new(),
+            send_buf: Vec::new(),
+            send_data_len: 0,
+        }
+    }
+}
+
+impl<S> WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Reads the client's HTTP `Upgrade: websocket` request off `stream`,
+    /// validates `Sec-WebSocket-Key`, and replies with the matching `101
+    /// Switching Protocols` response, returning an adapter that carries the
+    /// trojan payload inside binary frames.
+    pub async fn server_handshake(mut stream: S) -> Result<Self> {
+        let mut request = Vec::with_capacity(512);
+        let mut chunk = [0u8; 512];
+        let head_end = loop {
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                bail!("EOF during websocket handshake");
+            }
+            request.extend_from_slice(&chunk[..n]);
+            if let Some(pos) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos;
+            }
+        };
+
+        let head = String::from_utf8_lossy(&request[..head_end]).into_owned();
+        debug!("websocket handshake request: {}", head);
+
+        let mut lines = head.lines();
+        let request_line = lines.next().unwrap_or("");
+        if !request_line.starts_with("GET ") {
+            bail!("websocket upgrade expected a GET request line, got {:?}", request_line);
+        }
+
+        let mut key = None;
+        let mut upgrade_to_websocket = false;
+        for line in lines {
+            let (name, value) = match line.split_once(':') {
+                Some(parts) => parts,
+                None => continue,
+            };
+            let value = value.trim();
+            if name.trim().eq_ignore_ascii_case("sec-websocket-key") {
+                key = Some(value.to_owned());
+            } else if name.trim().eq_ignore_ascii_case("upgrade")
+                && value.eq_ignore_ascii_case("websocket")
+            {
+                upgrade_to_websocket = true;
+            }
+        }
+
+        if !upgrade_to_websocket {
+            bail!("websocket upgrade request is missing `Upgrade: websocket`");
+        }
+        let key = key.ok_or_else(|| anyhow!("websocket upgrade request is missing Sec-WebSocket-Key"))?;
+
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+            sec_websocket_accept(&key)
+        );
+        stream.write_all(response.as_bytes()).await?;
+        stream.flush().await?;
+
+        let mut ws = WsStream::new(stream);
+        ws.recv_raw = request[head_end + 4..].to_vec();
+        Ok(ws)
+    }
+}
+
+impl<S> AsyncRead for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        loop {
+            if !self.recv_payload.is_empty() {
+                let n = std::cmp::min(buf.remaining(), self.recv_payload.len());
+                buf.put_slice(&self.recv_payload[..n]);
+                self.recv_payload.drain(..n);
+                return Poll::Ready(Ok(()));
+            }
+
+            match parse_frame(&self.recv_raw) {
+                Some((frame, consumed)) => {
+                    self.recv_raw.drain(..consumed);
+                    match frame {
+                        WsFrame::Data { payload, fin } => {
+                            if fin {
+                                if self.fragment_buf.is_empty() {
+                                    self.recv_payload = payload;
+                                } else {
+                                    self.fragment_buf.extend_from_slice(&payload);
+                                    self.recv_payload = std::mem::take(&mut self.fragment_buf);
+                                }
+                            } else {
+                                self.fragment_buf.extend_from_slice(&payload);
+                            }
+                        }
+                        WsFrame::Ping(payload) => {
+                            // Best-effort: queued ahead of the next
+                            // application write rather than written here,
+                            // since we only hold a read-path `Context`.
+                            self.pending_ctrl
+                                .extend_from_slice(&encode_frame(OPCODE_PONG, &payload, false));
+                        }
+                        WsFrame::Pong(_) => (),
+                        WsFrame::Close => return Poll::Ready(Ok(())),
+                    }
+                }
+                None => {
+                    let me = &mut *self;
+                    let mut tmp = [0u8; 4096];
+                    let mut read_buf = ReadBuf::new(&mut tmp);
+                    match Pin::new(&mut me.inner).poll_read(cx, &mut read_buf)? {
+                        Poll::Ready(()) => {
+                            if read_buf.filled().is_empty() {
+                                return Poll::Ready(Ok(()));
+                            }
+                            me.recv_raw.extend_from_slice(read_buf.filled());
+                        }
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        if !self.pending_ctrl.is_empty() {
+            let me = &mut *self;
+            match Pin::new(&mut me.inner).poll_write(cx, &me.pending_ctrl)? {
+                Poll::Ready(0) => return Poll::Ready(Ok(0)),
+                Poll::Ready(n) if n < me.pending_ctrl.len() => {
+                    me.pending_ctrl.drain(..n);
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                Poll::Ready(_) => me.pending_ctrl.clear(),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        if self.send_buf.is_empty() {
+            self.send_data_len = buf.len();
+            // Servers must never mask a frame.
+            self.send_buf = encode_frame(OPCODE_BINARY, buf, false);
+        }
+
+        let me = &mut *self;
+        match Pin::new(&mut me.inner).poll_write(cx, &me.send_buf)? {
+            Poll::Ready(0) => Poll::Ready(Ok(0)),
+            Poll::Ready(n) if n < me.send_buf.len() => {
+                me.send_buf.drain(..n);
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+            Poll::Ready(_) => {
+                me.send_buf.clear();
+                Poll::Ready(Ok(me.send_data_len))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl<S> Splitable for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Debug + Send + 'static,
+{
+    type R = ReadHalf<WsStream<S>>;
+    type W = WriteHalf<WsStream<S>>;
+
+    fn split(self) -> (Self::R, Self::W) {
+        split(self)
+    }
+}
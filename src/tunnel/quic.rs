@@ -1,13 +1,62 @@
 use crate::args::Opt;
 use anyhow::*;
 use quinn::*;
+use std::collections::HashMap;
 use std::net::ToSocketAddrs;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
 use tokio::{fs, io};
 use tracing::*;
 
-#[allow(dead_code)]
-pub const ALPN_QUIC_HTTP: &[&[u8]] = &[b"hq-29"];
+/// Congestion controller selectable via `--congestion`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CongestionControl {
+    Cubic,
+    NewReno,
+    Bbr,
+}
+
+/// Builds the transport configuration shared by both the client
+/// (`quic_tunnel_tx`) and server (`quic_tunnel_rx`) endpoints, applying the
+/// `--quic-idle-timeout`, `--quic-keepalive`, `--quic-max-bidi-streams`,
+/// `--quic-initial-window` and `--congestion` options.
+fn build_transport_config(options: &Opt) -> Result<TransportConfig> {
+    let mut transport_config = TransportConfig::default();
+
+    transport_config.max_idle_timeout(Some(Duration::from_secs(options.quic_idle_timeout)))?;
+
+    transport_config.keep_alive_interval(if options.quic_keepalive == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(options.quic_keepalive))
+    });
+
+    transport_config.max_concurrent_bidi_streams(options.quic_max_bidi_streams)?;
+
+    match options.congestion_control {
+        CongestionControl::Cubic => {
+            let mut cubic = congestion::CubicConfig::default();
+            cubic.initial_window(options.quic_initial_window);
+            transport_config.congestion_controller_factory(Arc::new(cubic));
+        }
+        CongestionControl::NewReno => {
+            let mut new_reno = congestion::NewRenoConfig::default();
+            new_reno.initial_window(options.quic_initial_window);
+            transport_config.congestion_controller_factory(Arc::new(new_reno));
+        }
+        CongestionControl::Bbr => {
+            // This quinn version doesn't ship a BBR controller; fall back to
+            // cubic rather than silently ignoring the user's choice.
+            warn!("BBR congestion control isn't available in this build, falling back to cubic");
+            let mut cubic = congestion::CubicConfig::default();
+            cubic.initial_window(options.quic_initial_window);
+            transport_config.congestion_controller_factory(Arc::new(cubic));
+        }
+    }
+
+    Ok(transport_config)
+}
 
 async fn load_cert(options: &Opt, client_config: &mut ClientConfigBuilder) -> Result<()> {
     if let Some(ca_path) = &options.ca {
@@ -42,12 +91,15 @@ pub async fn quic_tunnel_tx(options: &Opt) -> Result<Connection> {
     trace!("1");
     let mut endpoint = quinn::Endpoint::builder();
     let mut client_config = quinn::ClientConfigBuilder::default();
-    client_config.protocols(ALPN_QUIC_HTTP);
+    client_config.protocols(&[options.quic_alpn.as_bytes()]);
 
     load_cert(options, &mut client_config).await?;
     trace!("2");
 
-    endpoint.default_client_config(client_config.build());
+    let mut client_config = client_config.build();
+    client_config.transport = Arc::new(build_transport_config(options)?);
+
+    endpoint.default_client_config(client_config);
 
     let (endpoint, _) = endpoint.bind(&"[::]:0".parse().unwrap())?;
 
@@ -65,13 +117,108 @@ pub async fn quic_tunnel_tx(options: &Opt) -> Result<Connection> {
     Ok(conn)
 }
 
+/// How long an idle pooled connection may sit unused before it's reaped,
+/// distinct from QUIC's own transport-level idle timeout.
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+struct PooledConnection {
+    conn: Connection,
+    last_used: Instant,
+}
+
+impl PooledConnection {
+    fn is_usable(&self) -> bool {
+        self.conn.close_reason().is_none() && self.last_used.elapsed() < POOL_IDLE_TIMEOUT
+    }
+}
+
+/// Caches live `quinn::Connection`s to the proxy, keyed by `(proxy_url,
+/// proxy_port)`, so opening a tunnel reuses QUIC's native stream
+/// multiplexing instead of paying for a fresh handshake every time. Dials a
+/// new connection only when the pool for that destination is empty or
+/// every pooled connection has gone idle/closing; `options.pool_size` caps
+/// how many connections per destination are kept around.
+pub struct ConnectionPool {
+    options: Opt,
+    pools: Mutex<HashMap<(String, String), Vec<PooledConnection>>>,
+}
+
+impl ConnectionPool {
+    pub fn new(options: Opt) -> Self {
+        Self {
+            options,
+            pools: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn key(&self) -> (String, String) {
+        (
+            self.options.proxy_url.clone(),
+            self.options.proxy_port.clone(),
+        )
+    }
+
+    /// Hands out a fresh bidirectional stream, reusing a healthy pooled
+    /// connection when one is available and dialing a new one otherwise.
+    pub async fn open_bi(&self) -> Result<(SendStream, RecvStream)> {
+        loop {
+            let reusable = {
+                let mut pools = self.pools.lock().unwrap();
+                let conns = pools.entry(self.key()).or_default();
+                conns.retain(PooledConnection::is_usable);
+                conns.last().map(|pooled| pooled.conn.clone())
+            };
+
+            let conn = match reusable {
+                Some(conn) => conn,
+                None => {
+                    debug!("quic connection pool empty, dialing a fresh connection");
+                    let conn = quic_tunnel_tx(&self.options).await?;
+                    let mut pools = self.pools.lock().unwrap();
+                    let conns = pools.entry(self.key()).or_default();
+                    if conns.len() < self.options.pool_size {
+                        conns.push(PooledConnection {
+                            conn: conn.clone(),
+                            last_used: Instant::now(),
+                        });
+                    }
+                    conn
+                }
+            };
+
+            match conn.open_bi().await {
+                Ok(streams) => {
+                    let mut pools = self.pools.lock().unwrap();
+                    if let Some(conns) = pools.get_mut(&self.key()) {
+                        if let Some(pooled) = conns.iter_mut().find(|p| p.conn.stable_id() == conn.stable_id())
+                        {
+                            pooled.last_used = Instant::now();
+                        }
+                    }
+                    return Ok(streams);
+                }
+                Err(e) => {
+                    // The connection died between the health check above and
+                    // this call (e.g. the server restarted); drop it from the
+                    // pool and retry with whatever's left, or dial fresh.
+                    debug!("pooled quic connection failed to open a stream: {}", e);
+                    let mut pools = self.pools.lock().unwrap();
+                    if let Some(conns) = pools.get_mut(&self.key()) {
+                        conns.retain(|p| p.conn.stable_id() != conn.stable_id());
+                    }
+                }
+            }
+        }
+    }
+}
+
 pub async fn quic_tunnel_rx(options: &Opt) -> Result<(Endpoint, Incoming)> {
-    let mut transport_config = quinn::TransportConfig::default();
-    transport_config.max_concurrent_uni_streams(0).unwrap();
+    let mut transport_config = build_transport_config(options)?;
+    transport_config.max_concurrent_uni_streams(0)?;
     let mut server_config = quinn::ServerConfig::default();
     server_config.transport = Arc::new(transport_config);
     let mut server_config = quinn::ServerConfigBuilder::new(server_config);
-    server_config.protocols(ALPN_QUIC_HTTP);
+    server_config.protocols(&[options.quic_alpn.as_bytes()]);
 
     server_config.use_stateless_retry(true);
 
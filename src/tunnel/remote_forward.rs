@@ -0,0 +1,428 @@
+//! Reverse (remote-to-local) port forwarding: the client asks the server to
+//! bind a listener on its side and tunnel every inbound connection back to
+//! a local address, the mirror image of the crate's normal forward-proxy
+//! direction where the client originates streams toward destinations the
+//! server reaches.
+//!
+//! Each `--remote-forward` gets its own dedicated QUIC connection (separate
+//! from the per-request [`ConnectionPool`](super::quic::ConnectionPool),
+//! since this one is long-lived rather than transient). Right after
+//! dialing, the client opens a control bi-stream and sends a
+//! [`RemoteForwardSpec`] describing the binding it wants; the server binds
+//! it and, for every inbound connection it then accepts, opens a fresh
+//! bi-stream on the same connection (tagged with a monotonic forward id,
+//! logged for diagnostics) and relays bytes between that inbound connection
+//! and the new stream. The client's `accept_bi` loop mirrors this: each new
+//! stream is dialed straight through to `local_addr`.
+
+use crate::args::Opt;
+use crate::tunnel::quic::quic_tunnel_tx;
+use anyhow::{Context, Result};
+use quinn::{Connection, RecvStream, SendStream};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+/// How long a remote-forward UDP peer may sit idle before its table entry is
+/// reclaimed, the same timeout the client-side association table in
+/// `client_udp_stream` uses.
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Which side originates the forwarded connection. Carried in the control
+/// message so the wire format has room for `LocalToRemote` (the crate's
+/// existing forward-proxy mode) to ride the same control channel one day;
+/// today only `RemoteToLocal` is implemented and anything else is rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardDirection {
+    /// The crate's normal mode: the client originates streams toward
+    /// destinations the server reaches.
+    LocalToRemote,
+    /// The server binds a listener and tunnels inbound connections back to
+    /// a local address on the client.
+    RemoteToLocal,
+}
+
+impl ForwardDirection {
+    fn get_code(self) -> u8 {
+        match self {
+            ForwardDirection::LocalToRemote => 0x00,
+            ForwardDirection::RemoteToLocal => 0x01,
+        }
+    }
+
+    fn from_code(code: u8) -> Result<Self> {
+        match code {
+            0x00 => Ok(ForwardDirection::LocalToRemote),
+            0x01 => Ok(ForwardDirection::RemoteToLocal),
+            other => anyhow::bail!("unknown remote-forward direction code {:#x}", other),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardProtocol {
+    Tcp,
+    Udp,
+}
+
+impl ForwardProtocol {
+    fn get_code(self) -> u8 {
+        match self {
+            ForwardProtocol::Tcp => 0x01,
+            ForwardProtocol::Udp => 0x03,
+        }
+    }
+
+    fn from_code(code: u8) -> Result<Self> {
+        match code {
+            0x01 => Ok(ForwardProtocol::Tcp),
+            0x03 => Ok(ForwardProtocol::Udp),
+            other => anyhow::bail!("unknown remote-forward protocol code {:#x}", other),
+        }
+    }
+}
+
+/// A parsed `--remote-forward <bind_addr>:<local_addr>[/tcp|udp]` flag,
+/// e.g. `--remote-forward 0.0.0.0:8080:127.0.0.1:80/tcp`.
+#[derive(Debug, Clone)]
+pub struct RemoteForwardSpec {
+    pub direction: ForwardDirection,
+    pub bind_addr: SocketAddr,
+    pub local_addr: SocketAddr,
+    pub protocol: ForwardProtocol,
+}
+
+/// Parses `--remote-forward <bind_host>:<bind_port>:<local_host>:<local_port>[/tcp|udp]`.
+/// Panics on malformed addresses, so a bad argument surfaces immediately
+/// at startup; the other `parse(from_str = ..)` flags on `Opt` instead fall
+/// back to a default. An unrecognized protocol suffix is taken as `tcp`.
+pub fn parse_remote_forward(s: &str) -> RemoteForwardSpec {
+    let (addrs, protocol) = match s.rsplit_once('/') {
+        Some((addrs, proto)) => (addrs, proto),
+        None => (s, "tcp"),
+    };
+    let protocol = match &protocol.to_lowercase()[..] {
+        "udp" => ForwardProtocol::Udp,
+        _ => ForwardProtocol::Tcp,
+    };
+
+    let parts: Vec<&str> = addrs.split(':').collect();
+    if parts.len() != 4 {
+        panic!(
+            "invalid --remote-forward {:?}, expected <bind_host>:<bind_port>:<local_host>:<local_port>[/tcp|udp]",
+            s
+        );
+    }
+    let bind_addr = format!("{}:{}", parts[0], parts[1]);
+    let local_addr = format!("{}:{}", parts[2], parts[3]);
+    RemoteForwardSpec {
+        direction: ForwardDirection::RemoteToLocal,
+        bind_addr: bind_addr
+            .parse()
+            .unwrap_or_else(|_| panic!("invalid bind address in --remote-forward: {}", bind_addr)),
+        local_addr: local_addr.parse().unwrap_or_else(|_| {
+            panic!("invalid local address in --remote-forward: {}", local_addr)
+        }),
+        protocol,
+    }
+}
+
+async fn write_string(stream: &mut SendStream, s: &str) -> Result<()> {
+    stream.write_u16(s.len() as u16).await?;
+    stream.write_all(s.as_bytes()).await?;
+    Ok(())
+}
+
+async fn read_string(stream: &mut RecvStream) -> Result<String> {
+    let len = stream.read_u16().await? as usize;
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf).await?;
+    Ok(String::from_utf8(buf)?)
+}
+
+async fn write_forward_request(stream: &mut SendStream, spec: &RemoteForwardSpec) -> Result<()> {
+    stream.write_u8(spec.direction.get_code()).await?;
+    stream.write_u8(spec.protocol.get_code()).await?;
+    write_string(stream, &spec.bind_addr.to_string()).await?;
+    write_string(stream, &spec.local_addr.to_string()).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+async fn read_forward_request(stream: &mut RecvStream) -> Result<RemoteForwardSpec> {
+    let direction = ForwardDirection::from_code(stream.read_u8().await?)?;
+    let protocol = ForwardProtocol::from_code(stream.read_u8().await?)?;
+    let bind_addr = read_string(stream)
+        .await?
+        .parse()
+        .context("invalid bind address in remote-forward control message")?;
+    let local_addr = read_string(stream)
+        .await?
+        .parse()
+        .context("invalid local address in remote-forward control message")?;
+    Ok(RemoteForwardSpec {
+        direction,
+        bind_addr,
+        local_addr,
+        protocol,
+    })
+}
+
+/// Client side of one `--remote-forward`: dials a dedicated connection to
+/// the proxy, registers the binding, then relays every stream the server
+/// opens back for it to `spec.local_addr`. Runs until the connection dies.
+pub async fn run_client_remote_forward(options: Opt, spec: RemoteForwardSpec) -> Result<()> {
+    let conn = quic_tunnel_tx(&options).await?;
+    let (mut control_send, _control_recv) = conn.open_bi().await?;
+    write_forward_request(&mut control_send, &spec).await?;
+    info!(
+        "remote-forward: registered {:?} {} -> {} with the proxy",
+        spec.protocol, spec.bind_addr, spec.local_addr
+    );
+
+    loop {
+        let (send, mut recv) = match conn.accept_bi().await {
+            Ok(streams) => streams,
+            Err(e) => {
+                warn!("remote-forward: control connection closed: {}", e);
+                return Ok(());
+            }
+        };
+
+        let forward_id = recv.read_u32().await.unwrap_or(0);
+        let local_addr = spec.local_addr;
+        match spec.protocol {
+            ForwardProtocol::Tcp => {
+                tokio::spawn(async move {
+                    if let Err(e) = relay_tcp_to_local(forward_id, send, recv, local_addr).await {
+                        debug!("remote-forward[{}]: tcp relay ended: {}", forward_id, e);
+                    }
+                });
+            }
+            ForwardProtocol::Udp => {
+                tokio::spawn(async move {
+                    if let Err(e) = relay_udp_to_local(forward_id, send, recv, local_addr).await {
+                        debug!("remote-forward[{}]: udp relay ended: {}", forward_id, e);
+                    }
+                });
+            }
+        }
+    }
+}
+
+async fn relay_tcp_to_local(
+    forward_id: u32,
+    mut tunnel_send: SendStream,
+    mut tunnel_recv: RecvStream,
+    local_addr: SocketAddr,
+) -> Result<()> {
+    debug!(
+        "remote-forward[{}]: dialing local {} for a new inbound connection",
+        forward_id, local_addr
+    );
+    let local = TcpStream::connect(local_addr).await?;
+    let (mut local_read, mut local_write) = local.into_split();
+
+    let upload = tokio::io::copy(&mut tunnel_recv, &mut local_write);
+    let download = tokio::io::copy(&mut local_read, &mut tunnel_send);
+    tokio::try_join!(upload, download)?;
+    Ok(())
+}
+
+async fn relay_udp_to_local(
+    forward_id: u32,
+    mut tunnel_send: SendStream,
+    mut tunnel_recv: RecvStream,
+    local_addr: SocketAddr,
+) -> Result<()> {
+    let local = UdpSocket::bind(("0.0.0.0", 0)).await?;
+    local.connect(local_addr).await?;
+    let local = Arc::new(local);
+
+    let upload = {
+        let local = local.clone();
+        async move {
+            loop {
+                let len = tunnel_recv.read_u16().await? as usize;
+                let mut datagram = vec![0u8; len];
+                tunnel_recv.read_exact(&mut datagram).await?;
+                local.send(&datagram).await?;
+            }
+            #[allow(unreachable_code)]
+            Ok::<(), anyhow::Error>(())
+        }
+    };
+    let download = async move {
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let n = local.recv(&mut buf).await?;
+            tunnel_send.write_u16(n as u16).await?;
+            tunnel_send.write_all(&buf[..n]).await?;
+        }
+        #[allow(unreachable_code)]
+        Ok::<(), anyhow::Error>(())
+    };
+
+    debug!("remote-forward[{}]: relaying udp to local {}", forward_id, local_addr);
+    tokio::select! {
+        res = upload => res,
+        res = download => res,
+    }
+}
+
+/// Server side: accepts the control bi-stream on a freshly-dialed client
+/// connection, reads its [`RemoteForwardSpec`], and binds + forwards
+/// accordingly. Runs until the binding (or the connection) is torn down.
+pub async fn run_server_remote_forward(conn: Connection) -> Result<()> {
+    let (_control_send, mut control_recv) = conn
+        .accept_bi()
+        .await
+        .context("remote-forward: client never opened a control stream")?;
+    let spec = read_forward_request(&mut control_recv).await?;
+    if spec.direction != ForwardDirection::RemoteToLocal {
+        anyhow::bail!(
+            "remote-forward: direction {:?} isn't implemented yet",
+            spec.direction
+        );
+    }
+
+    info!(
+        "remote-forward: binding {} to relay {:?} connections back to the client's {}",
+        spec.bind_addr, spec.protocol, spec.local_addr
+    );
+
+    match spec.protocol {
+        ForwardProtocol::Tcp => bind_and_forward_tcp(conn, spec.bind_addr).await,
+        ForwardProtocol::Udp => bind_and_forward_udp(conn, spec.bind_addr).await,
+    }
+}
+
+async fn bind_and_forward_tcp(conn: Connection, bind_addr: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(bind_addr).await?;
+    let next_forward_id = AtomicU32::new(1);
+
+    loop {
+        let (inbound, peer) = listener.accept().await?;
+        let forward_id = next_forward_id.fetch_add(1, Ordering::Relaxed);
+        let conn = conn.clone();
+
+        tokio::spawn(async move {
+            debug!("remote-forward[{}]: accepted tcp connection from {}", forward_id, peer);
+            let result: Result<()> = async {
+                let (mut tunnel_send, mut tunnel_recv) = conn.open_bi().await?;
+                tunnel_send.write_u32(forward_id).await?;
+
+                let (mut local_read, mut local_write) = inbound.into_split();
+                let upload = tokio::io::copy(&mut local_read, &mut tunnel_send);
+                let download = tokio::io::copy(&mut tunnel_recv, &mut local_write);
+                tokio::try_join!(upload, download)?;
+                Ok(())
+            }
+            .await;
+
+            if let Err(e) = result {
+                debug!("remote-forward[{}]: relay for {} ended: {}", forward_id, peer, e);
+            }
+        });
+    }
+}
+
+/// A [`bind_and_forward_udp`] peer table entry: the relay task ends when
+/// its tunnel stream closes (dropping its `rx`, which closes `tx`), but a
+/// closed `tx` and an idle-but-alive one both need to be pruned so a
+/// silently-failed peer isn't black-holed forever.
+struct UdpSession {
+    tx: mpsc::Sender<Vec<u8>>,
+    /// Shared with the relay task's download half, which refreshes it on
+    /// every `send_to` -- the recv loop in `bind_and_forward_udp` only sees uplink traffic,
+    /// so a download-heavy, uplink-quiet peer would otherwise look idle.
+    last_seen: Arc<Mutex<Instant>>,
+}
+
+fn reap_idle_udp_sessions(sessions: &mut HashMap<SocketAddr, UdpSession>) {
+    sessions.retain(|peer, session| {
+        let alive = !session.tx.is_closed()
+            && session.last_seen.lock().unwrap().elapsed() < SESSION_IDLE_TIMEOUT;
+        if !alive {
+            debug!("remote-forward: evicting udp peer {}", peer);
+        }
+        alive
+    });
+}
+
+async fn bind_and_forward_udp(conn: Connection, bind_addr: SocketAddr) -> Result<()> {
+    let socket = Arc::new(UdpSocket::bind(bind_addr).await?);
+    let next_forward_id = AtomicU32::new(1);
+    let mut sessions: HashMap<SocketAddr, UdpSession> = HashMap::new();
+
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let (n, peer) = socket.recv_from(&mut buf).await?;
+        let datagram = buf[..n].to_vec();
+
+        reap_idle_udp_sessions(&mut sessions);
+
+        if let Some(session) = sessions.get_mut(&peer) {
+            *session.last_seen.lock().unwrap() = Instant::now();
+            let _ = session.tx.try_send(datagram);
+            continue;
+        }
+
+        let forward_id = next_forward_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(32);
+        let _ = tx.try_send(datagram);
+        let last_seen = Arc::new(Mutex::new(Instant::now()));
+        sessions.insert(
+            peer,
+            UdpSession {
+                tx,
+                last_seen: last_seen.clone(),
+            },
+        );
+
+        let conn = conn.clone();
+        let socket = socket.clone();
+        tokio::spawn(async move {
+            debug!("remote-forward[{}]: new udp peer {}", forward_id, peer);
+            let result: Result<()> = async {
+                let (mut tunnel_send, mut tunnel_recv) = conn.open_bi().await?;
+                tunnel_send.write_u32(forward_id).await?;
+
+                let upload = async {
+                    while let Some(datagram) = rx.recv().await {
+                        tunnel_send.write_u16(datagram.len() as u16).await?;
+                        tunnel_send.write_all(&datagram).await?;
+                    }
+                    Ok::<(), anyhow::Error>(())
+                };
+                let download = async {
+                    loop {
+                        let len = tunnel_recv.read_u16().await? as usize;
+                        let mut datagram = vec![0u8; len];
+                        tunnel_recv.read_exact(&mut datagram).await?;
+                        socket.send_to(&datagram, peer).await?;
+                        *last_seen.lock().unwrap() = Instant::now();
+                    }
+                    #[allow(unreachable_code)]
+                    Ok::<(), anyhow::Error>(())
+                };
+                tokio::select! {
+                    res = upload => res,
+                    res = download => res,
+                }
+            }
+            .await;
+
+            if let Err(e) = result {
+                debug!("remote-forward[{}]: udp relay for {} ended: {}", forward_id, peer, e);
+            }
+        });
+    }
+}
@@ -0,0 +1,87 @@
+use crate::protocol::HASH_LEN;
+use sha2::{Digest, Sha224};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::Path;
+
+/// One registered server account. Carries the label a connection is
+/// attributed to (from `--user`/`--users`, or `"default"` for the legacy
+/// `-w`/`--password-hash` flag), with room for the per-user limits the
+/// traffic-limit feature will add.
+#[derive(Debug, Clone)]
+pub struct UserInfo {
+    pub label: String,
+}
+
+/// Hashes `password` the same way the trojan request header's user field
+/// is hashed, so the result can be looked up straight out of a [`UserDb`].
+pub fn hash_password(password: &str) -> String {
+    let mut hasher = Sha224::new();
+    hasher.update(password);
+    let digest = hasher.finalize();
+    let mut hash = String::with_capacity(HASH_LEN);
+    for byte in digest {
+        write!(&mut hash, "{:02x}", byte).unwrap();
+    }
+    hash
+}
+
+/// Registered users keyed by their SHA-224 password hash — the same hash
+/// the trojan request header carries — so the server accept path can look
+/// an incoming connection straight up instead of comparing against a
+/// single password.
+#[derive(Debug, Clone, Default)]
+pub struct UserDb {
+    users: HashMap<String, UserInfo>,
+}
+
+impl UserDb {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, password: &str, label: String) {
+        self.insert_hash(hash_password(password), label);
+    }
+
+    pub fn insert_hash(&mut self, hash: String, label: String) {
+        self.users.insert(hash, UserInfo { label });
+    }
+
+    /// Looks up the user attributed to `hash`, the value carried in the
+    /// trojan request header.
+    pub fn lookup(&self, hash: &str) -> Option<&UserInfo> {
+        self.users.get(hash)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+}
+
+/// Parses one `name:password` entry, as given to `--user`.
+pub fn parse_user_entry(entry: &str) -> Option<(String, String)> {
+    entry
+        .split_once(':')
+        .map(|(name, password)| (name.to_owned(), password.to_owned()))
+}
+
+/// Loads `name:password` lines from `path` (as given to `--users`) into
+/// `db`. Blank lines and `#`-prefixed comments are skipped.
+pub fn load_users_file(db: &mut UserDb, path: &Path) -> std::io::Result<()> {
+    let contents = std::fs::read_to_string(path)?;
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some((name, password)) = parse_user_entry(line) {
+            db.insert(&password, name);
+        }
+    }
+    Ok(())
+}
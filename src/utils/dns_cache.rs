@@ -0,0 +1,104 @@
+//! A TTL-aware, size-capped DNS cache shared by every split
+//! [`ServerUdpSendStream`](super::ServerUdpSendStream), so a chatty UDP flow
+//! to a hostname destination resolves through the cache instead of
+//! spawning a blocking `ToSocketAddrs` call (and hitting the system
+//! resolver) on every packet.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Floor/ceiling clamp applied to a record's DNS TTL before it's cached, so
+/// a misconfigured authority (TTL of 0, or unreasonably long) can't thrash
+/// the cache or pin a stale record forever.
+const MIN_TTL: Duration = Duration::from_secs(5);
+const MAX_TTL: Duration = Duration::from_secs(300);
+
+/// Cap on distinct hostnames cached: once this many are held, the
+/// least-recently-looked-up entry is evicted before a new one is inserted.
+const MAX_ENTRIES: usize = 4096;
+
+struct CacheEntry {
+    addrs: Vec<SocketAddr>,
+    expires_at: Instant,
+    last_used: Instant,
+}
+
+pub struct DnsCache {
+    resolver: TokioAsyncResolver,
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+impl DnsCache {
+    pub fn new() -> std::io::Result<Self> {
+        let resolver = TokioAsyncResolver::tokio_from_system_conf()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        Ok(Self {
+            resolver,
+            entries: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Returns cached, unexpired addresses for `name` without touching the
+    /// resolver; `None` means the caller must drive [`resolve`](Self::resolve).
+    pub fn lookup(&self, name: &str) -> Option<Vec<SocketAddr>> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(name)?;
+        if entry.expires_at <= Instant::now() {
+            entries.remove(name);
+            return None;
+        }
+        entry.last_used = Instant::now();
+        Some(entry.addrs.clone())
+    }
+
+    /// Resolves `name` against the system resolver and caches the result
+    /// under its DNS TTL, clamped to [`MIN_TTL`, `MAX_TTL`].
+    pub async fn resolve(&self, name: String, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+        let lookup = self
+            .resolver
+            .lookup_ip(name.as_str())
+            .await
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+
+        let ttl_secs = lookup
+            .as_lookup()
+            .record_iter()
+            .map(|record| record.ttl())
+            .min()
+            .unwrap_or(MIN_TTL.as_secs() as u32);
+        let ttl = Duration::from_secs(ttl_secs as u64).clamp(MIN_TTL, MAX_TTL);
+
+        let addrs: Vec<SocketAddr> = lookup.iter().map(|ip| SocketAddr::new(ip, port)).collect();
+
+        if !addrs.is_empty() {
+            let mut entries = self.entries.lock().unwrap();
+            if entries.len() >= MAX_ENTRIES && !entries.contains_key(&name) {
+                evict_oldest(&mut entries);
+            }
+            entries.insert(
+                name,
+                CacheEntry {
+                    addrs: addrs.clone(),
+                    expires_at: Instant::now() + ttl,
+                    last_used: Instant::now(),
+                },
+            );
+        }
+
+        Ok(addrs)
+    }
+}
+
+fn evict_oldest(entries: &mut HashMap<String, CacheEntry>) {
+    if let Some(oldest) = entries
+        .iter()
+        .min_by_key(|(_, entry)| entry.last_used)
+        .map(|(name, _)| name.clone())
+    {
+        entries.remove(&oldest);
+    }
+}
@@ -0,0 +1,25 @@
+#[cfg(feature = "unix_socket")]
+use std::path::PathBuf;
+
+/// A local listen/connect endpoint: either a regular TCP address, or (behind
+/// the `unix_socket` feature) an `AF_UNIX` socket path. Lets a `unix:/path`
+/// target be accepted anywhere a `host:port` one is today.
+#[derive(Debug, Clone)]
+pub enum ListenEndpoint {
+    Tcp(String),
+    #[cfg(feature = "unix_socket")]
+    Unix(PathBuf),
+}
+
+impl ListenEndpoint {
+    /// Parses `addr` as a `unix:/path/to.sock` endpoint when the
+    /// `unix_socket` feature is enabled and the prefix matches, falling
+    /// back to a plain TCP address otherwise.
+    pub fn parse(addr: &str) -> Self {
+        #[cfg(feature = "unix_socket")]
+        if let Some(path) = addr.strip_prefix("unix:") {
+            return ListenEndpoint::Unix(PathBuf::from(path));
+        }
+        ListenEndpoint::Tcp(addr.to_owned())
+    }
+}
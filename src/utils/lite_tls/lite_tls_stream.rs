@@ -2,7 +2,8 @@ use std::{ops::DerefMut, time::Duration};
 
 use super::{
     error::eof_err,
-    tls_relay_buffer::{Seen0x17, TlsRelayBuffer, TlsVersion},
+    tls_record_codec::MAX_PROTECTED_RECORD_PAYLOAD_LEN,
+    tls_relay_buffer::{extract_server_hello_alpn, Seen0x17, TlsRelayBuffer, TlsVersion},
 };
 use crate::{
     protocol::{LEAVE_TLS_COMMAND, LITE_TLS_HANDSHAKE_TIMEOUT},
@@ -30,29 +31,72 @@ pub(super) enum Direction {
     Outbound,
 }
 
+/// ALPN protocols the LiteTLS fast path can relay cleanly. `h2`'s framing
+/// would be corrupted by treating the stream as plain HTTP/1.x, so it isn't
+/// included here.
+const LITE_TLS_COMPATIBLE_ALPN: &[&str] = &["http/1.1"];
+
 pub struct LiteTlsStream {
     inbound_buf: TlsRelayBuffer,
     outbound_buf: TlsRelayBuffer,
     recieved_0x17: Seen0x17,
     side: LiteTlsEndpointSide,
+    /// ALPN selected by the server, once the ServerHello has been seen.
+    negotiated_alpn: Option<String>,
 }
 
 impl LiteTlsStream {
     pub fn new_client_endpoint() -> Self {
         Self {
-            inbound_buf: TlsRelayBuffer::new(),
-            outbound_buf: TlsRelayBuffer::new(),
+            inbound_buf: TlsRelayBuffer::new(MAX_PROTECTED_RECORD_PAYLOAD_LEN),
+            outbound_buf: TlsRelayBuffer::new(MAX_PROTECTED_RECORD_PAYLOAD_LEN),
             recieved_0x17: Seen0x17::None,
             side: LiteTlsEndpointSide::ClientSide,
+            negotiated_alpn: None,
         }
     }
 
     pub fn new_server_endpoint() -> Self {
         Self {
-            inbound_buf: TlsRelayBuffer::new(),
-            outbound_buf: TlsRelayBuffer::new(),
+            inbound_buf: TlsRelayBuffer::new(MAX_PROTECTED_RECORD_PAYLOAD_LEN),
+            outbound_buf: TlsRelayBuffer::new(MAX_PROTECTED_RECORD_PAYLOAD_LEN),
             recieved_0x17: Seen0x17::None,
             side: LiteTlsEndpointSide::ServerSide,
+            negotiated_alpn: None,
+        }
+    }
+
+    /// The ALPN protocol negotiated by the two peers, once the ServerHello
+    /// has been observed. `None` if no ALPN extension was exchanged (or it
+    /// hasn't been seen yet).
+    pub fn negotiated_alpn(&self) -> Option<&str> {
+        self.negotiated_alpn.as_deref()
+    }
+
+    /// Tries to pull the server's selected ALPN protocol out of whatever of
+    /// the ServerHello has been buffered so far. A no-op once it's known,
+    /// or while the ServerHello is still incomplete.
+    fn try_capture_negotiated_alpn(&mut self) {
+        if self.negotiated_alpn.is_some() {
+            return;
+        }
+        if let Some(alpn) = extract_server_hello_alpn(&self.outbound_buf) {
+            self.negotiated_alpn = Some(alpn);
+        }
+    }
+
+    /// Rejects the LiteTLS fast path when the negotiated ALPN isn't one the
+    /// plaintext relay can carry cleanly (e.g. `h2`), so the caller falls
+    /// back to the existing full TLS passthrough via `Err(Invalid)`.
+    fn check_alpn_compatible(&self) -> Result<()> {
+        match &self.negotiated_alpn {
+            Some(alpn) if !LITE_TLS_COMPATIBLE_ALPN.contains(&alpn.as_str()) => {
+                Err(Error::new(ParserError::Invalid(format!(
+                    "negotiated ALPN {:?} can't be carried by the LiteTLS fast path",
+                    alpn
+                ))))
+            }
+            _ => Ok(()),
         }
     }
 
@@ -353,22 +397,37 @@ impl LiteTlsStream {
                 }
             }
 
+            if let Direction::Outbound = dir {
+                self.try_capture_negotiated_alpn();
+            }
+
             use LiteTlsEndpointSide::*;
             use TlsVersion::*;
+            let client_early_data = self.inbound_buf.early_data_offered();
             match match dir {
                 Direction::Inbound => &mut self.inbound_buf,
                 Direction::Outbound => &mut self.outbound_buf,
             }
-            .find_key_packets(&mut self.recieved_0x17, dir)
+            .find_key_packets(&mut self.recieved_0x17, dir, client_early_data)
             {
                 Ok(Tls12) => {
-                    return match self.side {
-                        ServerSide => self.handshake_tls12_server(inbound).await,
-                        ClientSide => self.handshake_tls12_client(outbound, inbound).await,
+                    // Must run before any `handshake_tls12_*` call: those
+                    // already write `LEAVE_TLS_COMMAND` and strip the TLS
+                    // framing, so an incompatible ALPN has to fall back to
+                    // full TLS passthrough (`Err(Invalid)`) before that
+                    // happens, not after.
+                    self.check_alpn_compatible()?;
+                    match self.side {
+                        ServerSide => self.handshake_tls12_server(inbound).await?,
+                        ClientSide => self.handshake_tls12_client(outbound, inbound).await?,
                     };
+                    return Ok(());
                 }
                 Ok(Tls13) => {
-                    return self.handshake_tls13(outbound, inbound).await;
+                    // Same ordering requirement as the `Tls12` arm above.
+                    self.check_alpn_compatible()?;
+                    self.handshake_tls13(outbound, inbound).await?;
+                    return Ok(());
                 }
                 Err(ParserError::Incomplete(_)) => (), // relay pending packets
                 Err(e @ ParserError::Invalid(_)) => {
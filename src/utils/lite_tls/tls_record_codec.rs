@@ -0,0 +1,137 @@
+//! `tokio_util::codec` framing for raw TLS record boundaries.
+//!
+//! `TlsRelayBuffer` only ever needs to know where one record ends and the
+//! next begins -- the 5-byte header (`content_type`, `legacy_version`,
+//! big-endian `u16` length) -- so [`TlsRecordCodec`] centralizes that math
+//! in one place instead of each cursor-walking call site re-deriving it.
+//! `decode`/`encode` make it usable behind a `Framed` the same way the
+//! Zebra message codec is, and [`peek_record_len`] is the bit of that math
+//! `TlsRelayBuffer` still needs while it's accumulating several records
+//! ahead of the cursor before deciding to flush them.
+
+use anyhow::Result;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Size of a TLS record header: 1 byte `content_type`, 2 bytes
+/// `legacy_version`, 2 bytes big-endian `length`.
+pub(super) const RECORD_HEADER_LEN: usize = 5;
+
+/// Largest plaintext record payload a compliant TLS stack ever sends
+/// (RFC 8446 §5.1).
+pub(super) const MAX_PLAINTEXT_RECORD_PAYLOAD_LEN: usize = 16384;
+
+/// Largest protected (post-handshake, AEAD-sealed) record payload: the
+/// plaintext max plus the allowed expansion for the inner content-type
+/// byte and authentication tag (RFC 8446 §5.2). This relay doesn't track
+/// enough handshake state to tell plaintext and protected records apart
+/// byte-for-byte, so it uses this more permissive bound as the single cap.
+pub(super) const MAX_PROTECTED_RECORD_PAYLOAD_LEN: usize = MAX_PLAINTEXT_RECORD_PAYLOAD_LEN + 256;
+
+/// Content type of the `LEAVE_TLS_COMMAND` sentinel LiteTLS slips into the
+/// record stream to signal "stop parsing records, relay raw bytes from
+/// here" -- not a real TLS content type, but `check_tls_packet` parses it
+/// through this same header math, so it has to stay on the accepted list.
+const LEAVE_TLS_SENTINEL_CONTENT_TYPE: u8 = 0xff;
+
+fn is_recognized_content_type(content_type: u8) -> bool {
+    matches!(
+        content_type,
+        0x14 | 0x15 | 0x16 | 0x17 | LEAVE_TLS_SENTINEL_CONTENT_TYPE
+    )
+}
+
+/// One TLS record, framed out of the raw byte stream by [`TlsRecordCodec`].
+#[derive(Debug, Clone)]
+pub(super) struct TlsRecord {
+    pub content_type: u8,
+    pub legacy_version: u16,
+    pub payload: Bytes,
+}
+
+/// Outcome of peeking a record header at the front of a buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum RecordHeader {
+    /// `buf` doesn't hold a complete 5-byte header yet.
+    Incomplete,
+    /// The header is complete but names an unrecognized content type or a
+    /// payload larger than `max_payload_len` -- never a legitimate record.
+    Invalid,
+    /// Total length (header + payload) of the record starting at `buf[0]`.
+    Len(usize),
+}
+
+/// Peeks the record header at the front of `buf`, rejecting content types
+/// outside the TLS record set (see [`is_recognized_content_type`]) and
+/// payloads over `max_payload_len` so a hostile peer can't declare a
+/// near-64KB length and force unbounded buffering before any of it is
+/// validated.
+pub(super) fn peek_record_len(buf: &[u8], max_payload_len: usize) -> RecordHeader {
+    if buf.len() < RECORD_HEADER_LEN {
+        return RecordHeader::Incomplete;
+    }
+    if !is_recognized_content_type(buf[0]) {
+        return RecordHeader::Invalid;
+    }
+    let payload_len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+    if payload_len > max_payload_len {
+        return RecordHeader::Invalid;
+    }
+    RecordHeader::Len(RECORD_HEADER_LEN + payload_len)
+}
+
+/// Frames a byte stream into [`TlsRecord`]s.
+#[derive(Debug)]
+pub(super) struct TlsRecordCodec {
+    max_payload_len: usize,
+}
+
+impl Default for TlsRecordCodec {
+    fn default() -> Self {
+        Self {
+            max_payload_len: MAX_PROTECTED_RECORD_PAYLOAD_LEN,
+        }
+    }
+}
+
+impl Decoder for TlsRecordCodec {
+    type Item = TlsRecord;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<TlsRecord>> {
+        let record_len = match peek_record_len(src, self.max_payload_len) {
+            RecordHeader::Len(len) => len,
+            RecordHeader::Incomplete => return Ok(None),
+            RecordHeader::Invalid => {
+                anyhow::bail!("unrecognized content type or oversized tls record")
+            }
+        };
+        if src.len() < record_len {
+            src.reserve(record_len - src.len());
+            return Ok(None);
+        }
+
+        let content_type = src[0];
+        let legacy_version = u16::from_be_bytes([src[1], src[2]]);
+        let mut record = src.split_to(record_len);
+        record.advance(RECORD_HEADER_LEN);
+        Ok(Some(TlsRecord {
+            content_type,
+            legacy_version,
+            payload: record.freeze(),
+        }))
+    }
+}
+
+impl Encoder<TlsRecord> for TlsRecordCodec {
+    type Error = anyhow::Error;
+
+    fn encode(&mut self, record: TlsRecord, dst: &mut BytesMut) -> Result<()> {
+        dst.reserve(RECORD_HEADER_LEN + record.payload.len());
+        dst.put_u8(record.content_type);
+        dst.put_u16(record.legacy_version);
+        dst.put_u16(record.payload.len() as u16);
+        dst.put_slice(&record.payload);
+        Ok(())
+    }
+}
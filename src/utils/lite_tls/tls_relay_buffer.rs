@@ -1,6 +1,11 @@
-use super::{error::eof_err, lite_tls_stream::Direction};
+use super::{
+    error::eof_err,
+    lite_tls_stream::Direction,
+    tls_record_codec::{peek_record_len, RecordHeader},
+};
 use crate::{expect_buf_len, utils::ParserError};
 use anyhow::{Error, Result};
+use bytes::{Buf, BytesMut};
 use std::{
     cmp::min,
     ops::{Deref, DerefMut},
@@ -18,13 +23,22 @@ pub(super) enum TlsVersion {
 
 #[derive(Debug)]
 pub struct TlsRelayBuffer {
-    inner: Vec<u8>,
+    inner: BytesMut,
     /// read cursor
     cursor: usize,
+    /// ALPN protocols offered by the ClientHello, if any were parsed out.
+    alpn_offered: Vec<String>,
+    /// Whether the ClientHello offered `early_data`/`pre_shared_key`, i.e.
+    /// may be followed by 0-RTT application data.
+    early_data_offered: bool,
+    /// Largest record payload [`Self::record_len_at`] accepts; anything a
+    /// peer declares above this is `ParserError::Invalid` rather than an
+    /// unbounded buffer grow.
+    max_record_payload_len: usize,
 }
 
 impl Deref for TlsRelayBuffer {
-    type Target = Vec<u8>;
+    type Target = BytesMut;
     fn deref(&self) -> &Self::Target {
         &self.inner
     }
@@ -40,21 +54,171 @@ fn extract_len(buf: &[u8]) -> usize {
     buf[0] as usize * 256 + buf[1] as usize
 }
 
+const ALPN_EXTENSION_TYPE: u16 = 16;
+const PRE_SHARED_KEY_EXTENSION_TYPE: u16 = 0x0029;
+const EARLY_DATA_EXTENSION_TYPE: u16 = 0x002a;
+
+fn parse_alpn_protocol_list(data: &[u8]) -> Option<Vec<String>> {
+    if data.len() < 2 {
+        return None;
+    }
+    let list_len = u16::from_be_bytes([data[0], data[1]]) as usize;
+    let end = 2 + list_len;
+    if data.len() < end {
+        return None;
+    }
+    let mut names = Vec::new();
+    let mut i = 2;
+    while i < end {
+        let name_len = *data.get(i)? as usize;
+        i += 1;
+        if i + name_len > end {
+            return None;
+        }
+        names.push(String::from_utf8_lossy(&data[i..i + name_len]).into_owned());
+        i += name_len;
+    }
+    Some(names)
+}
+
+/// Walks a TLS extensions block (`extensions_length` + extensions),
+/// calling `visit(ext_type, ext_data)` for each one. `None` if the block
+/// is malformed or incomplete; `Some(())` once every extension has been
+/// visited.
+fn walk_extensions(record: &[u8], mut i: usize, mut visit: impl FnMut(u16, &[u8])) -> Option<()> {
+    if record.len() < i + 2 {
+        return None;
+    }
+    let ext_total_len = extract_len(&record[i..]);
+    i += 2;
+    let ext_end = i + ext_total_len;
+    if record.len() < ext_end {
+        return None;
+    }
+    while i + 4 <= ext_end {
+        let ext_type = u16::from_be_bytes([record[i], record[i + 1]]);
+        let ext_len = extract_len(&record[i + 2..]);
+        let data_start = i + 4;
+        if data_start + ext_len > ext_end {
+            return None;
+        }
+        visit(ext_type, &record[data_start..data_start + ext_len]);
+        i = data_start + ext_len;
+    }
+    Some(())
+}
+
+/// Scans a TLS extensions block for the ALPN `ProtocolNameList` (extension
+/// type 16).
+fn find_alpn_in_extensions(record: &[u8], i: usize) -> Option<Vec<String>> {
+    let mut alpn = None;
+    walk_extensions(record, i, |ext_type, data| {
+        if ext_type == ALPN_EXTENSION_TYPE && alpn.is_none() {
+            alpn = parse_alpn_protocol_list(data);
+        }
+    })?;
+    alpn
+}
+
+/// Scans a ClientHello's extensions block for `early_data` (0x002a) or
+/// `pre_shared_key` (0x0029): either means the client may follow this
+/// ClientHello with 0-RTT application data before any server response.
+fn find_early_data_offer(record: &[u8], i: usize) -> bool {
+    let mut offered = false;
+    let _ = walk_extensions(record, i, |ext_type, _| {
+        if ext_type == EARLY_DATA_EXTENSION_TYPE || ext_type == PRE_SHARED_KEY_EXTENSION_TYPE {
+            offered = true;
+        }
+    });
+    offered
+}
+
+/// Offset of the extensions block in a full ClientHello record (including
+/// the 5-byte record header): past `client_version`, `random`, the
+/// session id, the cipher suites list and the compression methods list.
+fn client_hello_extensions_offset(record: &[u8]) -> Option<usize> {
+    // record header(5) + handshake header(4) + client_version(2) + random(32)
+    let mut i = 5 + 4 + 2 + 32;
+    if record.len() < i + 1 {
+        return None;
+    }
+    let session_id_len = record[i] as usize;
+    i += 1 + session_id_len;
+
+    if record.len() < i + 2 {
+        return None;
+    }
+    let cipher_suites_len = extract_len(&record[i..]);
+    i += 2 + cipher_suites_len;
+
+    if record.len() < i + 1 {
+        return None;
+    }
+    let compression_methods_len = record[i] as usize;
+    i += 1 + compression_methods_len;
+
+    Some(i)
+}
+
+/// Extracts the client's offered ALPN protocol list from a full ClientHello
+/// record (including the 5-byte record header).
+pub(super) fn extract_client_hello_alpn(record: &[u8]) -> Option<Vec<String>> {
+    find_alpn_in_extensions(record, client_hello_extensions_offset(record)?)
+}
+
+/// Whether a full ClientHello record offers `early_data` or
+/// `pre_shared_key`, i.e. may be followed by 0-RTT application data.
+pub(super) fn client_hello_offers_early_data(record: &[u8]) -> bool {
+    match client_hello_extensions_offset(record) {
+        Some(i) => find_early_data_offer(record, i),
+        None => false,
+    }
+}
+
+/// Extracts the server's selected ALPN protocol from a full ServerHello
+/// record (including the 5-byte record header).
+pub(super) fn extract_server_hello_alpn(record: &[u8]) -> Option<String> {
+    // record header(5) + handshake header(4) + server_version(2) + random(32)
+    let mut i = 5 + 4 + 2 + 32;
+    if record.len() < i + 1 {
+        return None;
+    }
+    let session_id_len = record[i] as usize;
+    i += 1 + session_id_len;
+
+    // cipher_suite(2) + compression_method(1)
+    i += 3;
+
+    find_alpn_in_extensions(record, i)?.into_iter().next()
+}
+
 #[derive(Debug, Clone, Copy)]
 pub(super) enum Seen0x17 {
     None,
+    /// Inbound 0x17 records arriving before any outbound 0x17, while the
+    /// ClientHello offered `early_data`/`pre_shared_key` -- 0-RTT
+    /// application data, not the TLS 1.2 full-handshake signal.
+    EarlyData,
     FromInbound,
     FromOutbound,
     BothDirections,
 }
 
 impl Seen0x17 {
-    fn witness(&mut self, dir: Direction) {
+    /// `client_early_data` is whether the ClientHello that started this
+    /// connection offered 0-RTT; it only matters for the very first
+    /// inbound 0x17, which is otherwise indistinguishable from one.
+    fn witness(&mut self, dir: Direction, client_early_data: bool) {
         use Direction::*;
         use Seen0x17::*;
         *self = match (*self, dir) {
+            (None, Inbound) if client_early_data => EarlyData,
             (None, Inbound) => FromInbound,
             (None, Outbound) => FromOutbound,
+            (EarlyData, Inbound) => EarlyData,
+            // The first outbound 0x17 resolves the 0-RTT guess either way:
+            // fall back to the ordinary detection from here on.
+            (EarlyData, Outbound) => FromOutbound,
             (FromInbound, Outbound) | (FromOutbound, Inbound) => BothDirections,
             (BothDirections, _) => unreachable!(),
             _ => return,
@@ -63,10 +227,17 @@ impl Seen0x17 {
 }
 
 impl TlsRelayBuffer {
-    pub fn new() -> Self {
+    /// `max_record_payload_len` caps the payload length a declared record
+    /// header is allowed to claim (see [`Self::record_len_at`]); pass
+    /// [`super::tls_record_codec::MAX_PROTECTED_RECORD_PAYLOAD_LEN`] unless
+    /// the caller has a reason to tighten it.
+    pub fn new(max_record_payload_len: usize) -> Self {
         Self {
-            inner: Vec::with_capacity(2048),
+            inner: BytesMut::with_capacity(2048),
             cursor: 0,
+            alpn_offered: Vec::new(),
+            early_data_offered: false,
+            max_record_payload_len,
         }
     }
     pub fn len(&self) -> usize {
@@ -74,9 +245,7 @@ impl TlsRelayBuffer {
     }
 
     pub fn reset(&mut self) {
-        unsafe {
-            self.inner.set_len(0);
-        }
+        self.inner.clear();
         self.cursor = 0;
     }
 
@@ -89,16 +258,9 @@ impl TlsRelayBuffer {
     }
 
     pub fn pop_checked_packets(&mut self) {
-        let new_len = self.inner.len() - min(self.cursor, self.inner.len());
-        for i in 0..new_len {
-            self.inner[i] = self.inner[self.cursor + i];
-        }
-
-        self.cursor -= self.inner.len() - new_len;
-
-        unsafe {
-            self.inner.set_len(new_len);
-        }
+        let consumed = min(self.cursor, self.inner.len());
+        self.inner.advance(consumed);
+        self.cursor -= consumed;
     }
 
     pub fn check_client_hello(&mut self) -> Result<(), ParserError> {
@@ -107,14 +269,52 @@ impl TlsRelayBuffer {
             // Not tls 1.2/1.3
             return Err(ParserError::Invalid("not tls 1.2/1.3[1]".into()));
         }
-        self.cursor = 5 + extract_len(&self.inner[3..]);
+        self.cursor = self.record_len_at(0)?;
         if self.cursor != self.inner.len() {
             // Not tls 1.2/1.3
             return Err(ParserError::Invalid("not tls 1.2/1.3[2]".into()));
         }
+        self.alpn_offered = extract_client_hello_alpn(&self.inner).unwrap_or_default();
+        self.early_data_offered = client_hello_offers_early_data(&self.inner);
         Ok(())
     }
 
+    pub(super) fn alpn_offered(&self) -> &[String] {
+        &self.alpn_offered
+    }
+
+    pub(super) fn early_data_offered(&self) -> bool {
+        self.early_data_offered
+    }
+
+    /// Validated length (header + payload) of the record starting at
+    /// `self.inner[offset..]`: rejects content types outside the TLS
+    /// record set and payloads over `self.max_record_payload_len` (see
+    /// [`peek_record_len`]), then reserves capacity for the whole record
+    /// up front so a legitimate large one doesn't force repeated
+    /// reallocation while it's still arriving.
+    fn record_len_at(&mut self, offset: usize) -> Result<usize, ParserError> {
+        let record_len = match peek_record_len(&self.inner[offset..], self.max_record_payload_len)
+        {
+            RecordHeader::Len(len) => len,
+            RecordHeader::Incomplete => {
+                return Err(ParserError::Incomplete("record header incomplete".into()))
+            }
+            RecordHeader::Invalid => {
+                return Err(ParserError::Invalid(
+                    "unrecognized content type or oversized tls record".into(),
+                ))
+            }
+        };
+        self.inner
+            .reserve((offset + record_len).saturating_sub(self.inner.len()));
+        Ok(record_len)
+    }
+
+    /// Advances `cursor` past the record starting at it, the same record
+    /// boundary [`TlsRecordCodec`](super::tls_record_codec::TlsRecordCodec)
+    /// decodes off a `Framed` stream -- [`Self::record_len_at`] is the one
+    /// place that does the 5-byte-header length math both share.
     pub(super) fn check_tls_packet(&mut self) -> Result<u8, ParserError> {
         expect_buf_len!(
             self.inner,
@@ -122,7 +322,7 @@ impl TlsRelayBuffer {
             "packet 0x16 (or sth) incomplete[1]"
         );
         let packet_type = self.inner[self.cursor];
-        self.cursor += 5 + extract_len(&self.inner[self.cursor + 3..]);
+        self.cursor += self.record_len_at(self.cursor)?;
         expect_buf_len!(
             self.inner,
             self.cursor,
@@ -131,10 +331,15 @@ impl TlsRelayBuffer {
         Ok(packet_type)
     }
 
+    /// `client_early_data` is the ClientHello's `early_data`/
+    /// `pre_shared_key` offer (see [`Self::early_data_offered`]), needed to
+    /// tell a genuine 0-RTT inbound 0x17 apart from the TLS 1.2
+    /// full-handshake signal of the same shape.
     pub(super) fn find_key_packets(
         &mut self,
         seen_0x17: &mut Seen0x17,
         dir: Direction,
+        client_early_data: bool,
     ) -> Result<TlsVersion, ParserError> {
         loop {
             expect_buf_len!(self.inner, self.cursor + 1, "find 0x17 incomplete");
@@ -142,7 +347,7 @@ impl TlsRelayBuffer {
                 0x17 => {
                     #[cfg(feature = "debug_info")]
                     debug!("found 0x17, already seen: {:?}", seen_0x17);
-                    seen_0x17.witness(dir);
+                    seen_0x17.witness(dir, client_early_data);
                     #[cfg(feature = "debug_info")]
                     debug!("now seen 0x17: {:?}", seen_0x17);
                     match seen_0x17 {
@@ -216,7 +421,7 @@ impl TlsRelayBuffer {
             match self.inner[self.cursor] {
                 0x14 => key_packet_seen = 1,
                 0x16 if key_packet_seen == 1 => {
-                    self.cursor += 5 + extract_len(&self.inner[self.cursor + 3..]);
+                    self.cursor += self.record_len_at(self.cursor)?;
                     while self.inner.len() < self.cursor {
                         if self.checked_packets().len() > 0 {
                             self.flush_checked(writer).await?;
@@ -230,7 +435,7 @@ impl TlsRelayBuffer {
                 }
                 _ => (),
             }
-            self.cursor += 5 + extract_len(&self.inner[self.cursor + 3..]);
+            self.cursor += self.record_len_at(self.cursor)?;
         }
     }
 
@@ -1,11 +1,16 @@
 mod client_tcp_stream;
 mod client_udp_stream;
+mod dns_cache;
+mod listen_endpoint;
 mod mix_addr;
+mod proxy_protocol;
 
 use bytes::BufMut;
 pub use client_tcp_stream::{ClientTcpRecvStream, ClientTcpStream};
-pub use client_udp_stream::{Socks5UdpRecvStream, Socks5UdpSendStream, Socks5UdpStream};
+pub use client_udp_stream::{Socks5UdpAssociation, Socks5UdpStream};
+pub use listen_endpoint::ListenEndpoint;
 pub use mix_addr::MixAddrType;
+pub use proxy_protocol::{write_proxy_protocol_header, ProxyProtocolMode};
 use tokio::io::ReadBuf;
 
 #[derive(Debug, err_derive::Error)]
@@ -103,3 +108,20 @@ impl<'a> CursoredBuffer for UdpRelayBuffer<'a> {
         self.cursor += len;
     }
 }
+
+impl<'a> UdpRelayBuffer<'a> {
+    /// Discards everything read so far, so the next datagram is written
+    /// starting from the front of the buffer again.
+    pub(crate) fn clear(&mut self) {
+        self.buf.clear();
+        self.cursor = 0;
+    }
+
+    /// Replaces the unread tail (from the current cursor onward) with
+    /// `data`. Used to splice a reassembled payload in place of the last
+    /// fragment's own bytes before it's handed to the caller.
+    pub(crate) fn replace_tail(&mut self, data: &[u8]) {
+        self.buf.truncate(self.cursor);
+        self.buf.extend_from_slice(data);
+    }
+}
@@ -1,28 +1,32 @@
+use super::dns_cache::DnsCache;
 use super::{MixAddrType, UdpRead, UdpRelayBuffer, UdpWrite};
 use futures::{ready, Future};
+use std::io;
 use std::net::SocketAddr;
-use std::vec;
-use std::{
-    pin::Pin,
-    task::{Context, Poll},
-};
-use tokio::task::spawn_blocking;
-use tokio::{net::UdpSocket, task::JoinHandle};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::net::UdpSocket;
 use tracing::*;
 
 pub struct ServerUdpStream {
     inner: UdpSocket,
+    dns_cache: Arc<DnsCache>,
 }
 
 impl ServerUdpStream {
-    pub fn new(inner: UdpSocket) -> Self {
-        Self { inner }
+    pub fn new(inner: UdpSocket) -> io::Result<Self> {
+        Ok(Self {
+            inner,
+            dns_cache: Arc::new(DnsCache::new()?),
+        })
     }
 
     pub fn split(&mut self) -> (ServerUdpSendStream, ServerUdpRecvStream) {
         (
             ServerUdpSendStream {
                 inner: &self.inner,
+                dns_cache: self.dns_cache.clone(),
                 addr_task: ResolveAddr::None,
             },
             ServerUdpRecvStream { inner: &self.inner },
@@ -30,12 +34,20 @@ impl ServerUdpStream {
     }
 }
 
-#[derive(Debug)]
 pub struct ServerUdpSendStream<'a> {
     inner: &'a UdpSocket,
+    dns_cache: Arc<DnsCache>,
     addr_task: ResolveAddr,
 }
 
+impl<'a> std::fmt::Debug for ServerUdpSendStream<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerUdpSendStream")
+            .field("addr_task", &self.addr_task)
+            .finish()
+    }
+}
+
 impl<'a> UdpWrite for ServerUdpSendStream<'a> {
     fn poll_proxy_stream_write(
         mut self: Pin<&mut Self>,
@@ -46,26 +58,26 @@ impl<'a> UdpWrite for ServerUdpSendStream<'a> {
         debug!("ServerUdpSendStream::poll_proxy_stream_write()");
         loop {
             match self.addr_task {
-                ResolveAddr::Pending(ref mut task) => {
-                    debug!(
-                        "ServerUdpSendStream::poll_proxy_stream_write() ResolveAddr::Pending({:?})",
-                        task
-                    );
-
-                    let poll_res = Pin::new(task).poll(cx);
-
+                ResolveAddr::Pending(ref mut fut) => {
                     debug!(
-                        "ServerUdpSendStream::poll_proxy_stream_write() ResolveAddr::Pending(), res: {:?}",
-                        poll_res
+                        "ServerUdpSendStream::poll_proxy_stream_write() ResolveAddr::Pending"
                     );
 
-                    match ready!(poll_res)??.next() {
-                        Some(x) => {
-                            self.addr_task = ResolveAddr::Ready(x);
-                        }
-                        None => {
+                    let poll_res = fut.as_mut().poll(cx);
+
+                    match ready!(poll_res) {
+                        Ok(addrs) => match addrs.into_iter().next() {
+                            Some(s_addr) => {
+                                self.addr_task = ResolveAddr::Ready(s_addr);
+                            }
+                            None => {
+                                self.addr_task = ResolveAddr::None;
+                                return Poll::Ready(Ok(0));
+                            }
+                        },
+                        Err(e) => {
                             self.addr_task = ResolveAddr::None;
-                            return Poll::Ready(Ok(0));
+                            return Poll::Ready(Err(e));
                         }
                     }
                 }
@@ -93,11 +105,23 @@ impl<'a> UdpWrite for ServerUdpSendStream<'a> {
                     use MixAddrType::*;
                     self.addr_task = match addr {
                         x @ V4(_) | x @ V6(_) => ResolveAddr::Ready(x.clone().to_socket_addrs()),
-                        Hostname((name, _)) => {
-                            let name = name.to_owned();
-                            ResolveAddr::Pending(spawn_blocking(move || {
-                                std::net::ToSocketAddrs::to_socket_addrs(&name)
-                            }))
+                        Hostname((name, port)) => {
+                            if let Some(addrs) = self.dns_cache.lookup(name) {
+                                match addrs.into_iter().next() {
+                                    Some(s_addr) => ResolveAddr::Ready(s_addr),
+                                    None => {
+                                        self.addr_task = ResolveAddr::None;
+                                        return Poll::Ready(Ok(0));
+                                    }
+                                }
+                            } else {
+                                let cache = self.dns_cache.clone();
+                                let name = name.to_owned();
+                                let port = *port;
+                                ResolveAddr::Pending(Box::pin(async move {
+                                    cache.resolve(name, port).await
+                                }))
+                            }
                         }
                         _ => panic!("unprecedented MixAddrType variant"),
                     };
@@ -107,13 +131,25 @@ impl<'a> UdpWrite for ServerUdpSendStream<'a> {
     }
 }
 
-#[derive(Debug)]
+type ResolveFuture =
+    Pin<Box<dyn Future<Output = std::io::Result<Vec<SocketAddr>>> + Send>>;
+
 enum ResolveAddr {
-    Pending(JoinHandle<std::io::Result<vec::IntoIter<SocketAddr>>>),
+    Pending(ResolveFuture),
     Ready(SocketAddr),
     None,
 }
 
+impl std::fmt::Debug for ResolveAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResolveAddr::Pending(_) => f.write_str("ResolveAddr::Pending"),
+            ResolveAddr::Ready(addr) => write!(f, "ResolveAddr::Ready({})", addr),
+            ResolveAddr::None => f.write_str("ResolveAddr::None"),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ServerUdpRecvStream<'a> {
     inner: &'a UdpSocket,
@@ -0,0 +1,377 @@
+//! Batched UDP I/O: accumulates multiple equal-sized datagram segments and
+//! flushes them with a single GSO-segmented `send_to` (`UDP_SEGMENT`), and
+//! drains incoming datagrams with one `recvmmsg` call, using `UDP_GRO` to
+//! let the kernel coalesce same-sized segments, instead of one syscall per
+//! datagram. `sendmmsg` is not used. Opt in via the
+//! `udp_batch` feature; platforms (or kernels) lacking the socket options
+//! fall back to one send/recv per packet transparently.
+//!
+//! GSO only splits a buffer into `segment_size`-sized datagrams (the final
+//! one may be shorter); it has no notion of per-datagram length, so it
+//! cannot carry arbitrary variable-length trojan/SOCKS5 frames without
+//! corrupting them with trailing padding. [`GsoBatch`] therefore only
+//! accepts segments that are already exactly `segment_size` (via
+//! [`GsoBatch::push`]), plus an optional shorter final segment (via
+//! [`GsoBatch::push_last`]) — i.e. runs of fixed-size datagrams such as
+//! MTU-sized bulk transfers, not arbitrarily-framed packets.
+
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+
+/// Max packets a [`GsoBatch`] accumulates, or messages a `recv_batch` call
+/// drains in one syscall, before the caller should flush/re-poll.
+pub const MAX_BATCH_SEGMENTS: usize = 32;
+
+/// Accumulates up to `MAX_BATCH_SEGMENTS` same-sized, same-destination
+/// datagram segments for a single GSO-segmented `send_to` (or one `send_to`
+/// per segment where GSO isn't available).
+#[derive(Debug)]
+pub struct GsoBatch {
+    segment_size: usize,
+    buf: Vec<u8>,
+    segments: usize,
+    /// Set once [`GsoBatch::push_last`] has closed the batch with a
+    /// shorter final segment; further pushes are rejected until cleared.
+    closed: bool,
+}
+
+impl GsoBatch {
+    pub fn new(segment_size: usize) -> Self {
+        Self {
+            segment_size,
+            buf: Vec::with_capacity(segment_size * MAX_BATCH_SEGMENTS),
+            segments: 0,
+            closed: false,
+        }
+    }
+
+    /// Buffers `data` as the next GSO segment. `data` must be exactly
+    /// `segment_size` bytes: GSO splits the flushed buffer into
+    /// fixed-size datagrams, so a shorter segment here would either be
+    /// rejected or (if padded) delivered with trailing garbage bytes the
+    /// receiver has no way to strip. Returns `false` (without buffering)
+    /// once the batch is full, already closed by [`GsoBatch::push_last`],
+    /// or `data.len() != segment_size`, signalling the caller to flush (or
+    /// send `data` through the unbatched path) first.
+    pub fn push(&mut self, data: &[u8]) -> bool {
+        if self.closed || self.segments >= MAX_BATCH_SEGMENTS || data.len() != self.segment_size {
+            return false;
+        }
+        self.buf.extend_from_slice(data);
+        self.segments += 1;
+        true
+    }
+
+    /// Like [`GsoBatch::push`], but allows `data` to be shorter than
+    /// `segment_size` (down to 1 byte) by closing the batch: true GSO
+    /// semantics only allow the final segment of a batch to be short, so
+    /// no further segments may be pushed until the batch is flushed and
+    /// cleared.
+    pub fn push_last(&mut self, data: &[u8]) -> bool {
+        if self.closed
+            || self.segments >= MAX_BATCH_SEGMENTS
+            || data.is_empty()
+            || data.len() > self.segment_size
+        {
+            return false;
+        }
+        self.buf.extend_from_slice(data);
+        self.segments += 1;
+        self.closed = true;
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.segments
+    }
+
+    fn clear(&mut self) {
+        self.buf.clear();
+        self.segments = 0;
+        self.closed = false;
+    }
+}
+
+/// Flushes every segment in `batch` to `dst` in as few syscalls as the
+/// platform allows, clearing it on success. Falls back to one `send_to`
+/// per segment when GSO isn't available.
+pub async fn send_batch(
+    socket: &UdpSocket,
+    batch: &mut GsoBatch,
+    dst: SocketAddr,
+) -> io::Result<usize> {
+    if batch.is_empty() {
+        return Ok(0);
+    }
+    let sent = imp::send_batch(socket, batch, dst).await?;
+    batch.clear();
+    Ok(sent)
+}
+
+/// Drains up to [`MAX_BATCH_SEGMENTS`] pending messages off `socket` in as
+/// few syscalls as the platform allows, splitting any `UDP_GRO`-coalesced
+/// buffer back into the individual datagrams the peer sent (so more than
+/// `MAX_BATCH_SEGMENTS` datagrams may be returned). Falls back to
+/// one `recv_from` per datagram when `recvmmsg` isn't available.
+pub async fn recv_batch(
+    socket: &UdpSocket,
+    segment_size: usize,
+) -> io::Result<Vec<(Vec<u8>, SocketAddr)>> {
+    imp::recv_batch(socket, segment_size).await
+}
+
+#[cfg(all(target_os = "linux", feature = "udp_batch"))]
+mod imp {
+    use super::{GsoBatch, MAX_BATCH_SEGMENTS};
+    use std::io;
+    use std::net::SocketAddr;
+    use std::os::unix::io::AsRawFd;
+    use tokio::net::UdpSocket;
+
+    /// `IPPROTO_UDP`-level socket option enabling GSO on outgoing datagrams
+    /// (segment size in bytes, as a `c_int`). Not yet exposed by the `libc`
+    /// crate under this name on every target, so the raw value is used
+    /// directly.
+    const UDP_SEGMENT: libc::c_int = 103;
+
+    /// `IPPROTO_UDP`-level socket option enabling GRO on incoming
+    /// datagrams: the kernel coalesces consecutive same-size segments from
+    /// one peer into a single buffer, handed back with a `UDP_GRO` cmsg
+    /// carrying the segment size to split on. Same caveat as
+    /// [`UDP_SEGMENT`]: not exposed by the `libc` crate under this name.
+    const UDP_GRO: libc::c_int = 104;
+
+    /// Segments a GRO recv buffer is never coalesced past, matching the
+    /// kernel's own cap (`UDP_MAX_SEGMENTS`). Used only to size the
+    /// per-slot receive buffer generously enough to hold a fully
+    /// coalesced datagram.
+    const MAX_GRO_SEGMENTS: usize = 64;
+
+    fn set_gso_segment_size(socket: &UdpSocket, size: u16) -> io::Result<()> {
+        let size = size as libc::c_int;
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_UDP,
+                UDP_SEGMENT,
+                &size as *const _ as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Best-effort: older kernels without `UDP_GRO` simply won't coalesce,
+    /// and `try_recvmmsg` already handles an un-coalesced (single-segment)
+    /// buffer correctly, so a failure here is not fatal.
+    fn set_gro(socket: &UdpSocket) {
+        let on: libc::c_int = 1;
+        unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_UDP,
+                UDP_GRO,
+                &on as *const _ as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            );
+        }
+    }
+
+    pub(super) async fn send_batch(
+        socket: &UdpSocket,
+        batch: &GsoBatch,
+        dst: SocketAddr,
+    ) -> io::Result<usize> {
+        // A single `send_to` carrying all segments back-to-back; with
+        // UDP_SEGMENT set the kernel splits it into `segment_size`-sized
+        // datagrams, trading the N syscalls of a naive loop for one.
+        match set_gso_segment_size(socket, batch.segment_size as u16) {
+            Ok(()) => socket.send_to(&batch.buf, dst).await,
+            // Older kernels without UDP_SEGMENT: fall back instead of
+            // failing the whole batch.
+            Err(_) => send_one_by_one(socket, batch, dst).await,
+        }
+    }
+
+    async fn send_one_by_one(
+        socket: &UdpSocket,
+        batch: &GsoBatch,
+        dst: SocketAddr,
+    ) -> io::Result<usize> {
+        let mut sent = 0;
+        for segment in batch.buf.chunks(batch.segment_size) {
+            sent += socket.send_to(segment, dst).await?;
+        }
+        Ok(sent)
+    }
+
+    pub(super) async fn recv_batch(
+        socket: &UdpSocket,
+        segment_size: usize,
+    ) -> io::Result<Vec<(Vec<u8>, SocketAddr)>> {
+        // Best-effort; a kernel without UDP_GRO just never coalesces and
+        // try_recvmmsg's split step degenerates to one packet per message.
+        set_gro(socket);
+        loop {
+            socket.readable().await?;
+            match try_recvmmsg(socket, segment_size) {
+                Ok(packets) => return Ok(packets),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Size of the recv buffer backing each `recvmmsg` slot: large enough
+    /// to hold a fully GRO-coalesced datagram, not just one segment.
+    fn gro_buf_len(segment_size: usize) -> usize {
+        segment_size.saturating_mul(MAX_GRO_SEGMENTS)
+    }
+
+    /// Reads the `UDP_GRO` cmsg (if any) off a received message, giving the
+    /// segment size the kernel coalesced `msg_hdr` with, or `None` if the
+    /// message wasn't coalesced (no cmsg present).
+    fn gro_segment_size(msg_hdr: &libc::msghdr) -> Option<u16> {
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(msg_hdr as *const libc::msghdr);
+            while !cmsg.is_null() {
+                let hdr = &*cmsg;
+                if hdr.cmsg_level == libc::SOL_UDP && hdr.cmsg_type == UDP_GRO {
+                    let data = libc::CMSG_DATA(cmsg) as *const u16;
+                    return Some(*data);
+                }
+                cmsg = libc::CMSG_NXTHDR(msg_hdr as *const libc::msghdr, cmsg);
+            }
+        }
+        None
+    }
+
+    fn try_recvmmsg(
+        socket: &UdpSocket,
+        segment_size: usize,
+    ) -> io::Result<Vec<(Vec<u8>, SocketAddr)>> {
+        let buf_len = gro_buf_len(segment_size);
+        let cmsg_len = unsafe { libc::CMSG_SPACE(std::mem::size_of::<libc::c_int>() as u32) } as usize;
+
+        let mut bufs: Vec<Vec<u8>> = (0..MAX_BATCH_SEGMENTS).map(|_| vec![0u8; buf_len]).collect();
+        let mut cmsg_bufs: Vec<Vec<u8>> =
+            (0..MAX_BATCH_SEGMENTS).map(|_| vec![0u8; cmsg_len]).collect();
+        let mut iovecs: Vec<libc::iovec> = bufs
+            .iter_mut()
+            .map(|b| libc::iovec {
+                iov_base: b.as_mut_ptr() as *mut libc::c_void,
+                iov_len: b.len(),
+            })
+            .collect();
+        let mut addrs: Vec<libc::sockaddr_storage> =
+            vec![unsafe { std::mem::zeroed() }; MAX_BATCH_SEGMENTS];
+        let mut msgs: Vec<libc::mmsghdr> = (0..MAX_BATCH_SEGMENTS)
+            .map(|i| libc::mmsghdr {
+                msg_hdr: libc::msghdr {
+                    msg_name: &mut addrs[i] as *mut _ as *mut libc::c_void,
+                    msg_namelen: std::mem::size_of::<libc::sockaddr_storage>() as u32,
+                    msg_iov: &mut iovecs[i] as *mut libc::iovec,
+                    msg_iovlen: 1,
+                    msg_control: cmsg_bufs[i].as_mut_ptr() as *mut libc::c_void,
+                    msg_controllen: cmsg_len as _,
+                    msg_flags: 0,
+                },
+                msg_len: 0,
+            })
+            .collect();
+
+        let received = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                MAX_BATCH_SEGMENTS as u32,
+                libc::MSG_DONTWAIT,
+                std::ptr::null_mut(),
+            )
+        };
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut packets = Vec::new();
+        for (i, buf) in bufs.into_iter().enumerate().take(received as usize) {
+            let mut buf = buf;
+            buf.truncate(msgs[i].msg_len as usize);
+            let peer = sockaddr_storage_to_socket_addr(&addrs[i])?;
+
+            // A GRO'd message is one coalesced buffer of several
+            // gro_segment_size-sized datagrams (the last possibly
+            // shorter); split it back into the individual datagrams the
+            // peer actually sent.
+            match gro_segment_size(&msgs[i].msg_hdr) {
+                Some(seg) if (seg as usize) < buf.len() => {
+                    for chunk in buf.chunks(seg as usize) {
+                        packets.push((chunk.to_vec(), peer));
+                    }
+                }
+                _ => packets.push((buf, peer)),
+            }
+        }
+        Ok(packets)
+    }
+
+    fn sockaddr_storage_to_socket_addr(
+        storage: &libc::sockaddr_storage,
+    ) -> io::Result<SocketAddr> {
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let addr: &libc::sockaddr_in =
+                    unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+                let ip = std::net::Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+                let port = u16::from_be(addr.sin_port);
+                Ok(SocketAddr::from((ip, port)))
+            }
+            libc::AF_INET6 => {
+                let addr: &libc::sockaddr_in6 =
+                    unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+                let ip = std::net::Ipv6Addr::from(addr.sin6_addr.s6_addr);
+                let port = u16::from_be(addr.sin6_port);
+                Ok(SocketAddr::from((ip, port)))
+            }
+            _ => Err(io::ErrorKind::InvalidData.into()),
+        }
+    }
+}
+
+#[cfg(not(all(target_os = "linux", feature = "udp_batch")))]
+mod imp {
+    use super::GsoBatch;
+    use std::io;
+    use std::net::SocketAddr;
+    use tokio::net::UdpSocket;
+
+    pub(super) async fn send_batch(
+        socket: &UdpSocket,
+        batch: &GsoBatch,
+        dst: SocketAddr,
+    ) -> io::Result<usize> {
+        let mut sent = 0;
+        for segment in batch.buf.chunks(batch.segment_size) {
+            sent += socket.send_to(segment, dst).await?;
+        }
+        Ok(sent)
+    }
+
+    pub(super) async fn recv_batch(
+        socket: &UdpSocket,
+        segment_size: usize,
+    ) -> io::Result<Vec<(Vec<u8>, SocketAddr)>> {
+        let mut datagram = vec![0u8; segment_size];
+        let (n, peer) = socket.recv_from(&mut datagram).await?;
+        datagram.truncate(n);
+        Ok(vec![(datagram, peer)])
+    }
+}
@@ -0,0 +1,436 @@
+//! io_uring-backed UDP transport (Linux only, opt in via the `io_uring`
+//! feature): submits `recv`/`send` SQEs and completes relay operations from
+//! their CQEs, instead of tokio's readiness-then-syscall two-step. Each half
+//! keeps at most one SQE in flight (one recv, one send) and waits for its
+//! completion before submitting the next. Every other target, and this one with
+//! the feature off, keeps using the plain `tokio::net::UdpSocket` path.
+//!
+//! Completion readiness is surfaced to tokio by registering the ring's
+//! eventfd with [`tokio::io::unix::AsyncFd`]: the ring notifies the eventfd
+//! whenever a CQE is posted, tokio wakes the relay task on that fd becoming
+//! readable, and the task then drains the completion queue.
+
+#![cfg(feature = "io_uring")]
+
+use crate::utils::{MixAddrType, UdpRead, UdpRelayBuffer, UdpWrite};
+use futures::task::AtomicWaker;
+use io_uring::{opcode, types, IoUring};
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::unix::AsyncFd;
+use tokio::net::UdpSocket;
+
+/// Submission/completion queue depth. One in-flight recv and one in-flight
+/// send per association is all the relay loop drives concurrently; this
+/// just leaves headroom so a resubmission never has to wait on a full SQ.
+const RING_ENTRIES: u32 = 8;
+
+const RECV_USER_DATA: u64 = 0;
+const SEND_USER_DATA: u64 = 1;
+
+struct Ring {
+    io_uring: IoUring,
+    socket_fd: RawFd,
+}
+
+/// Slots the recv and send halves' completions are routed into once drained
+/// off the shared ring, so whichever half happens to drain a batch of CQEs
+/// doesn't discard the other's. At most one recv and one send SQE are ever
+/// in flight at a time, so a single slot per direction is enough.
+#[derive(Default)]
+struct Completions {
+    recv: Option<i32>,
+    send: Option<i32>,
+}
+
+/// Shared io_uring instance plus the socket it drives. Kept behind a
+/// `Mutex` the same way [`Socks5UdpStream`](super::super::Socks5UdpStream)
+/// shares its session table: submissions are quick, non-blocking
+/// `push`/`submit` calls, so a std mutex is enough.
+struct Shared {
+    ring: Mutex<Ring>,
+    eventfd: AsyncFd<EventFd>,
+    completions: Mutex<Completions>,
+    // Woken explicitly once the other half's drain has filled this half's
+    // `Completions` slot, since the two halves polling the same `eventfd`
+    // can't rely on both being woken by it (`AsyncFd` only tracks the most
+    // recently registered waiter per direction).
+    recv_waker: AtomicWaker,
+    send_waker: AtomicWaker,
+    // Keeps the socket's fd valid for the ring's lifetime; never touched
+    // directly once `socket_fd` has been cached on `Ring`.
+    _socket: UdpSocket,
+}
+
+/// A bound UDP socket driven by io_uring instead of tokio's reactor. Split
+/// it with [`split`](IoUringUdpSocket::split) into a recv half and a send
+/// half, mirroring how `TrojanUdpStream` is generic over a split
+/// read/write transport.
+pub struct IoUringUdpSocket {
+    shared: Arc<Shared>,
+}
+
+impl IoUringUdpSocket {
+    pub fn new(socket: UdpSocket) -> io::Result<Self> {
+        let io_uring = IoUring::new(RING_ENTRIES)?;
+        let eventfd = EventFd::new()?;
+        io_uring.submitter().register_eventfd(eventfd.as_raw_fd())?;
+        let socket_fd = socket.as_raw_fd();
+        Ok(Self {
+            shared: Arc::new(Shared {
+                ring: Mutex::new(Ring { io_uring, socket_fd }),
+                eventfd: AsyncFd::new(eventfd)?,
+                completions: Mutex::new(Completions::default()),
+                recv_waker: AtomicWaker::new(),
+                send_waker: AtomicWaker::new(),
+                _socket: socket,
+            }),
+        })
+    }
+
+    /// Splits into independent recv/send halves sharing the same ring, the
+    /// io_uring counterpart to `Splitable::split`'s owned-half pattern used
+    /// by every other transport.
+    pub fn split(self) -> (IoUringUdpRecvHalf, IoUringUdpSendHalf) {
+        (
+            IoUringUdpRecvHalf {
+                shared: self.shared.clone(),
+                buf: Box::new([0u8; 65536]),
+                inflight: false,
+                src_storage: Box::new(unsafe { std::mem::zeroed() }),
+                iovec: Box::new(libc::iovec {
+                    iov_base: std::ptr::null_mut(),
+                    iov_len: 0,
+                }),
+                msghdr: Box::new(unsafe { std::mem::zeroed() }),
+            },
+            IoUringUdpSendHalf {
+                shared: self.shared,
+                inflight: false,
+                dst_storage: Box::new(unsafe { std::mem::zeroed() }),
+                iovec: Box::new(libc::iovec {
+                    iov_base: std::ptr::null_mut(),
+                    iov_len: 0,
+                }),
+                msghdr: Box::new(unsafe { std::mem::zeroed() }),
+            },
+        )
+    }
+}
+
+/// Fills `storage` with `addr`'s bytes and returns the `msg_namelen` to use
+/// for it, the same V4/V6 layout `sockaddr_storage_to_socket_addr` in
+/// `gso_batch` reads back the other way.
+fn write_sockaddr(storage: &mut libc::sockaddr_storage, addr: SocketAddr) -> u32 {
+    match addr {
+        SocketAddr::V4(addr) => {
+            let sin = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: addr.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from_ne_bytes(addr.ip().octets()),
+                },
+                sin_zero: [0; 8],
+            };
+            unsafe {
+                std::ptr::write(storage as *mut _ as *mut libc::sockaddr_in, sin);
+            }
+            std::mem::size_of::<libc::sockaddr_in>() as u32
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: addr.port().to_be(),
+                sin6_flowinfo: addr.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                },
+                sin6_scope_id: addr.scope_id(),
+            };
+            unsafe {
+                std::ptr::write(storage as *mut _ as *mut libc::sockaddr_in6, sin6);
+            }
+            std::mem::size_of::<libc::sockaddr_in6>() as u32
+        }
+    }
+}
+
+/// Returns the `Completions` slot for `user_data` (its `AtomicWaker` comes
+/// from [`waker_for`]).
+fn completion_slot(completions: &mut Completions, user_data: u64) -> &mut Option<i32> {
+    match user_data {
+        RECV_USER_DATA => &mut completions.recv,
+        SEND_USER_DATA => &mut completions.send,
+        _ => unreachable!("io_uring_udp only submits RECV_USER_DATA/SEND_USER_DATA"),
+    }
+}
+
+fn waker_for(shared: &Shared, user_data: u64) -> &AtomicWaker {
+    match user_data {
+        RECV_USER_DATA => &shared.recv_waker,
+        SEND_USER_DATA => &shared.send_waker,
+        _ => unreachable!("io_uring_udp only submits RECV_USER_DATA/SEND_USER_DATA"),
+    }
+}
+
+/// Returns the result of `user_data`'s completion if one has posted,
+/// draining the shared ring's CQ as needed. Both halves' completions can
+/// land in the same drain (whichever half happens to poll first after the
+/// ring's eventfd fires), so a drained CQE that isn't `user_data`'s is
+/// routed into the other half's `Completions` slot and its task explicitly
+/// woken, rather than discarded -- otherwise the other half could wait on a
+/// CQE that was already consumed out from under it.
+fn poll_completion(shared: &Shared, user_data: u64, cx: &mut Context<'_>) -> Option<i32> {
+    // Register before checking the slot, so a completion the other half
+    // drains and routes to us between the check below and our caller
+    // returning `Pending` still wakes us via `wake()` rather than being
+    // missed.
+    waker_for(shared, user_data).register(cx.waker());
+
+    {
+        let mut completions = shared.completions.lock().unwrap();
+        if let Some(res) = completion_slot(&mut completions, user_data).take() {
+            return Some(res);
+        }
+    }
+
+    let mut ring = shared.ring.lock().unwrap();
+    let mut completions = shared.completions.lock().unwrap();
+    for cqe in ring.io_uring.completion() {
+        let res = cqe.result();
+        match cqe.user_data() {
+            RECV_USER_DATA => {
+                completions.recv = Some(res);
+                shared.recv_waker.wake();
+            }
+            SEND_USER_DATA => {
+                completions.send = Some(res);
+                shared.send_waker.wake();
+            }
+            _ => (),
+        }
+    }
+    completion_slot(&mut completions, user_data).take()
+}
+
+pub struct IoUringUdpRecvHalf {
+    shared: Arc<Shared>,
+    buf: Box<[u8; 65536]>,
+    inflight: bool,
+    // Source address + iovec + msghdr for the in-flight `RecvMsg` SQE,
+    // owned here for the same lifetime reason `IoUringUdpSendHalf` owns its
+    // `SendMsg` scratch: io_uring needs them to stay put from submission
+    // through completion.
+    src_storage: Box<libc::sockaddr_storage>,
+    iovec: Box<libc::iovec>,
+    msghdr: Box<libc::msghdr>,
+}
+
+impl UdpRead for IoUringUdpRecvHalf {
+    fn poll_proxy_stream_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut UdpRelayBuffer,
+    ) -> Poll<io::Result<MixAddrType>> {
+        loop {
+            if !self.inflight {
+                self.iovec.iov_base = self.buf.as_mut_ptr() as *mut libc::c_void;
+                self.iovec.iov_len = self.buf.len();
+                self.msghdr.msg_name = self.src_storage.as_mut() as *mut _ as *mut libc::c_void;
+                self.msghdr.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as u32;
+                self.msghdr.msg_iov = self.iovec.as_mut() as *mut libc::iovec;
+                self.msghdr.msg_iovlen = 1;
+
+                let socket_fd = self.shared.ring.lock().unwrap().socket_fd;
+                // `RecvMsg` (unlike `Recv`) fills in `msg_name`/`msg_namelen`
+                // with the datagram's source address on completion, which a
+                // demultiplexing relay needs to route return traffic. Built
+                // before re-taking the ring lock below, since the `msghdr`
+                // borrow and the lock guard can't be held at once.
+                let sqe = opcode::RecvMsg::new(types::Fd(socket_fd), self.msghdr.as_mut())
+                    .build()
+                    .user_data(RECV_USER_DATA);
+                let mut ring = self.shared.ring.lock().unwrap();
+                // Safety: `buf`/`iovec`/`msghdr`/`src_storage` are owned by
+                // `self` and not touched again until the matching CQE is
+                // observed below, satisfying the SQE's lifetime contract.
+                unsafe {
+                    ring.io_uring
+                        .submission()
+                        .push(&sqe)
+                        .map_err(|_| io::Error::from(io::ErrorKind::WouldBlock))?;
+                }
+                ring.io_uring.submit()?;
+                drop(ring);
+                self.inflight = true;
+            }
+
+            if let Some(res) = poll_completion(&self.shared, RECV_USER_DATA, cx) {
+                self.inflight = false;
+                if res < 0 {
+                    return Poll::Ready(Err(io::Error::from_raw_os_error(-res)));
+                }
+                let n = res as usize;
+                buf.clear();
+                buf.replace_tail(&self.buf[..n]);
+                let peer = sockaddr_storage_to_mix_addr(&self.src_storage)?;
+                return Poll::Ready(Ok(peer));
+            }
+
+            let mut guard = match self.shared.eventfd.poll_read_ready(cx) {
+                Poll::Ready(guard) => guard?,
+                Poll::Pending => return Poll::Pending,
+            };
+            // Drain the eventfd counter so the next CQE re-arms readiness.
+            drain_eventfd(guard.get_inner());
+            guard.clear_ready();
+        }
+    }
+}
+
+/// Converts a filled-in `sockaddr_storage` (as `RecvMsg` leaves it in
+/// `msg_name`) into the `MixAddrType` a demultiplexing `UdpRead` caller
+/// routes return traffic by, mirroring `gso_batch`'s equivalent for
+/// `recvmmsg`.
+fn sockaddr_storage_to_mix_addr(storage: &libc::sockaddr_storage) -> io::Result<MixAddrType> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr: &libc::sockaddr_in =
+                unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            let ip = std::net::Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            let port = u16::from_be(addr.sin_port);
+            Ok(MixAddrType::V4((ip.octets(), port)))
+        }
+        libc::AF_INET6 => {
+            let addr: &libc::sockaddr_in6 =
+                unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            let ip = std::net::Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            let port = u16::from_be(addr.sin6_port);
+            Ok(MixAddrType::V6((ip.octets(), port)))
+        }
+        _ => Err(io::ErrorKind::InvalidData.into()),
+    }
+}
+
+pub struct IoUringUdpSendHalf {
+    shared: Arc<Shared>,
+    inflight: bool,
+    // Destination + iovec + msghdr for the in-flight `SendMsg` SQE. io_uring
+    // requires these to stay put (and alive) from submission through
+    // completion, so they're owned here rather than built on the stack.
+    dst_storage: Box<libc::sockaddr_storage>,
+    iovec: Box<libc::iovec>,
+    msghdr: Box<libc::msghdr>,
+}
+
+impl UdpWrite for IoUringUdpSendHalf {
+    fn poll_proxy_stream_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        addr: &MixAddrType,
+    ) -> Poll<io::Result<usize>> {
+        // The io_uring send path only handles addresses already resolved to
+        // a `SocketAddr`; a hostname destination needs the same async DNS
+        // resolution `ServerUdpSendStream` does, which doesn't fit this
+        // SQE-submit-then-complete loop and is left to the tokio path.
+        let dst: SocketAddr = match addr {
+            MixAddrType::V4(_) | MixAddrType::V6(_) => addr.clone().to_socket_addrs(),
+            _ => return Poll::Ready(Err(io::ErrorKind::InvalidInput.into())),
+        };
+
+        loop {
+            if !self.inflight {
+                let addrlen = write_sockaddr(&mut self.dst_storage, dst);
+                self.iovec.iov_base = buf.as_ptr() as *mut libc::c_void;
+                self.iovec.iov_len = buf.len();
+                self.msghdr.msg_name = self.dst_storage.as_mut() as *mut _ as *mut libc::c_void;
+                self.msghdr.msg_namelen = addrlen;
+                self.msghdr.msg_iov = self.iovec.as_mut() as *mut libc::iovec;
+                self.msghdr.msg_iovlen = 1;
+
+                let mut ring = self.shared.ring.lock().unwrap();
+                let socket_fd = ring.socket_fd;
+                let sqe = opcode::SendMsg::new(types::Fd(socket_fd), self.msghdr.as_ref())
+                    .build()
+                    .user_data(SEND_USER_DATA);
+                // Safety: `dst_storage`/`iovec`/`msghdr` are owned by
+                // `self` and not touched again until the matching CQE is
+                // observed below, satisfying the SQE's lifetime contract.
+                unsafe {
+                    ring.io_uring
+                        .submission()
+                        .push(&sqe)
+                        .map_err(|_| io::Error::from(io::ErrorKind::WouldBlock))?;
+                }
+                ring.io_uring.submit()?;
+                drop(ring);
+                self.inflight = true;
+            }
+
+            if let Some(res) = poll_completion(&self.shared, SEND_USER_DATA, cx) {
+                self.inflight = false;
+                if res < 0 {
+                    return Poll::Ready(Err(io::Error::from_raw_os_error(-res)));
+                }
+                return Poll::Ready(Ok(res as usize));
+            }
+
+            let mut guard = match self.shared.eventfd.poll_read_ready(cx) {
+                Poll::Ready(guard) => guard?,
+                Poll::Pending => return Poll::Pending,
+            };
+            drain_eventfd(guard.get_inner());
+            guard.clear_ready();
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Minimal eventfd wrapper: just enough to hand a raw fd to
+/// `register_eventfd` and to `AsyncFd` for readiness polling.
+struct EventFd(RawFd);
+
+impl EventFd {
+    fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(fd))
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for EventFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
+/// Drains the eventfd's counter (a non-blocking 8-byte read) so the ring's
+/// next CQE post re-arms readiness instead of `AsyncFd` staying stuck ready.
+fn drain_eventfd(fd: &EventFd) {
+    let mut discard = [0u8; 8];
+    unsafe {
+        libc::read(fd.as_raw_fd(), discard.as_mut_ptr() as *mut libc::c_void, 8);
+    }
+}
@@ -0,0 +1,149 @@
+//! Relays UDP over QUIC's unreliable datagram extension instead of the
+//! ordered, reliable stream `TrojanUdpStream` rides on. Each UDP packet
+//! maps to exactly one QUIC datagram carrying the trojan UDP header
+//! (`MixAddrType`) followed directly by the payload — no length prefix is
+//! needed, since the datagram's own boundary already is the packet
+//! boundary, unlike the stream path which has to frame one.
+//!
+//! Datagrams can't be fragmented, so a packet that doesn't fit the
+//! connection's negotiated `max_datagram_size` is never sent here: callers
+//! see an explicit error and are expected to fall back to the
+//! stream-based `TrojanUdpStream`/`copy_udp` path for that packet rather
+//! than having it silently dropped.
+
+use crate::utils::{
+    CursoredBuffer, ExtendableFromSlice, MixAddrType, UdpRead, UdpRelayBuffer, UdpWrite,
+};
+use quinn::Connection;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tracing::{debug, warn};
+
+/// Whether `conn` has negotiated datagram support at all, and if so, how
+/// large a trojan UDP packet (address header + payload) it can carry in
+/// one QUIC datagram. `None` means the datagram path isn't usable on this
+/// connection and every packet must go over the stream-based relay.
+pub fn max_packet_size(conn: &Connection) -> Option<usize> {
+    conn.max_datagram_size()
+}
+
+/// Read half of the QUIC-datagram UDP relay.
+pub struct QuicDatagramRecv {
+    conn: Connection,
+    pending: Option<Pin<Box<dyn Future<Output = Result<bytes::Bytes, quinn::ConnectionError>> + Send>>>,
+}
+
+impl QuicDatagramRecv {
+    pub fn new(conn: Connection) -> Self {
+        Self {
+            conn,
+            pending: None,
+        }
+    }
+}
+
+impl UdpRead for QuicDatagramRecv {
+    fn poll_proxy_stream_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut UdpRelayBuffer,
+    ) -> Poll<io::Result<MixAddrType>> {
+        loop {
+            if self.pending.is_none() {
+                let conn = self.conn.clone();
+                self.pending = Some(Box::pin(async move { conn.read_datagram().await }));
+            }
+
+            let datagram = match self.pending.as_mut().unwrap().as_mut().poll(cx) {
+                Poll::Ready(res) => {
+                    self.pending = None;
+                    res
+                }
+                Poll::Pending => return Poll::Pending,
+            };
+
+            let datagram = match datagram {
+                Ok(datagram) => datagram,
+                Err(e) => return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, e))),
+            };
+
+            buf.clear();
+            buf.extend_from_slice(&datagram);
+            match MixAddrType::from_encoded(buf) {
+                Ok(addr) => return Poll::Ready(Ok(addr)),
+                Err(_) => {
+                    warn!("quic datagram udp relay: dropping malformed datagram");
+                    continue;
+                }
+            }
+        }
+    }
+}
+
+/// Write half of the QUIC-datagram UDP relay.
+pub struct QuicDatagramSend {
+    conn: Connection,
+}
+
+impl QuicDatagramSend {
+    pub fn new(conn: Connection) -> Self {
+        Self { conn }
+    }
+
+    /// `true` if `addr` + a `len`-byte payload fit in one QUIC datagram on
+    /// this connection; `false` means the caller must fall back to the
+    /// stream-based relay for this packet instead of calling
+    /// `poll_proxy_stream_write`.
+    pub fn fits(&self, addr: &MixAddrType, len: usize) -> bool {
+        match max_packet_size(&self.conn) {
+            Some(max) => addr.encoded_len() + len <= max,
+            None => false,
+        }
+    }
+}
+
+impl UdpWrite for QuicDatagramSend {
+    fn poll_proxy_stream_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+        addr: &MixAddrType,
+    ) -> Poll<io::Result<usize>> {
+        if !self.fits(addr, buf.len()) {
+            // The caller is expected to check `fits` before handing a
+            // packet to this relay; ending up here anyway means the
+            // negotiated size shrank (e.g. a path MTU change) between the
+            // check and the send. Surface it rather than dropping the
+            // packet silently, so the caller can fall back for this one.
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "packet exceeds the connection's max_datagram_size; fall back to the stream relay",
+            )));
+        }
+
+        let mut datagram = UdpRelayBuffer::new();
+        addr.write_buf(&mut datagram);
+        datagram.extend_from_slice(buf);
+
+        match self
+            .conn
+            .send_datagram(bytes::Bytes::copy_from_slice(datagram.as_bytes()))
+        {
+            Ok(()) => Poll::Ready(Ok(buf.len())),
+            Err(e) => {
+                debug!("quic datagram udp relay: send_datagram failed: {}", e);
+                Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, e)))
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,125 @@
+//! RFC 6455 frame codec shared by the client and server WebSocket
+//! transports: the FIN+opcode byte, 7/16/64-bit extended payload length
+//! and masking are factored out here, separate from the handshake and
+//! `AsyncRead`/`AsyncWrite` plumbing each side builds on top of it -- the
+//! same transport/codec split the karyon net crate uses for its
+//! websocket backend.
+
+use rand::RngCore;
+
+pub(crate) const OPCODE_CONTINUATION: u8 = 0x0;
+pub(crate) const OPCODE_BINARY: u8 = 0x2;
+pub(crate) const OPCODE_CLOSE: u8 = 0x8;
+pub(crate) const OPCODE_PING: u8 = 0x9;
+pub(crate) const OPCODE_PONG: u8 = 0xA;
+
+/// One frame off the wire, after masking (if any) has already been undone.
+#[derive(Debug)]
+pub(crate) enum WsFrame {
+    /// A `Binary`/`Continuation` payload chunk. `fin` is `false` while more
+    /// continuation frames are still to come.
+    Data { payload: Vec<u8>, fin: bool },
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close,
+}
+
+/// Encodes `payload` as a single unfragmented, final frame. `mask` must be
+/// `true` for client -> server frames (RFC 6455 requires a fresh random
+/// masking key) and `false` for server -> client frames, which must never
+/// be masked.
+pub(crate) fn encode_frame(opcode: u8, payload: &[u8], mask: bool) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    frame.push(0x80 | opcode); // FIN + opcode
+
+    let mask_bit = if mask { 0x80 } else { 0x00 };
+    let len = payload.len();
+    if len < 126 {
+        frame.push(mask_bit | len as u8);
+    } else if len <= 0xFFFF {
+        frame.push(mask_bit | 126);
+        frame.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        frame.push(mask_bit | 127);
+        frame.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+
+    if mask {
+        let mut key = [0u8; 4];
+        rand::thread_rng().fill_bytes(&mut key);
+        frame.extend_from_slice(&key);
+        let start = frame.len();
+        frame.extend_from_slice(payload);
+        for (i, b) in frame[start..].iter_mut().enumerate() {
+            *b ^= key[i % 4];
+        }
+    } else {
+        frame.extend_from_slice(payload);
+    }
+
+    frame
+}
+
+/// Parses one frame from the front of `buf`, returning `(frame, bytes
+/// consumed)`, or `None` if `buf` doesn't hold a complete frame yet.
+///
+/// Per RFC 6455 a client's frames are always masked and a server's are
+/// never masked. This parses either, unmasking in place when a mask key
+/// is present, so it can serve both the client and server transports.
+pub(crate) fn parse_frame(buf: &[u8]) -> Option<(WsFrame, usize)> {
+    if buf.len() < 2 {
+        return None;
+    }
+    let fin = buf[0] & 0x80 != 0;
+    let opcode = buf[0] & 0x0F;
+    let masked = buf[1] & 0x80 != 0;
+    let mut len = (buf[1] & 0x7F) as usize;
+    let mut idx = 2;
+
+    if len == 126 {
+        if buf.len() < 4 {
+            return None;
+        }
+        len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        idx = 4;
+    } else if len == 127 {
+        if buf.len() < 10 {
+            return None;
+        }
+        let mut len_bytes = [0u8; 8];
+        len_bytes.copy_from_slice(&buf[2..10]);
+        len = u64::from_be_bytes(len_bytes) as usize;
+        idx = 10;
+    }
+
+    let mask_key = if masked {
+        if buf.len() < idx + 4 {
+            return None;
+        }
+        let key = [buf[idx], buf[idx + 1], buf[idx + 2], buf[idx + 3]];
+        idx += 4;
+        Some(key)
+    } else {
+        None
+    };
+
+    if buf.len() < idx + len {
+        return None;
+    }
+
+    let mut payload = buf[idx..idx + len].to_vec();
+    if let Some(key) = mask_key {
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= key[i % 4];
+        }
+    }
+
+    let consumed = idx + len;
+    let frame = match opcode {
+        OPCODE_CLOSE => WsFrame::Close,
+        OPCODE_PING => WsFrame::Ping(payload),
+        OPCODE_PONG => WsFrame::Pong(payload),
+        _ => WsFrame::Data { payload, fin },
+    };
+    Some((frame, consumed))
+}